@@ -0,0 +1,68 @@
+use rand::Rng;
+use std::time::Duration;
+
+// Exponential backoff with jitter for reconnecting feeds and streams.
+// Each call to next_delay doubles the base delay (capped at max) and then
+// picks a random delay in [base/2, base] so that several tasks reconnecting
+// at the same time don't hammer the remote end in lockstep.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        return Backoff {
+            initial,
+            max,
+            attempt: 0,
+        };
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.initial.as_secs_f64() * 2_f64.powi(self.attempt.min(30) as i32);
+        let base = exp.min(self.max.as_secs_f64());
+        self.attempt += 1;
+
+        let jitter: f64 = rand::thread_rng().gen_range(0.5..=1.0);
+        return Duration::from_secs_f64(base * jitter);
+    }
+
+    // call this once a connection is healthy again
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn attempt(&self) -> u32 {
+        return self.attempt;
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        for _ in 0..20 {
+            let d = b.next_delay();
+            assert!(d <= Duration::from_secs(8));
+        }
+        assert_eq!(b.attempt(), 20);
+        // once capped we're always at least half of max
+        assert!(b.next_delay() >= Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..5 {
+            b.next_delay();
+        }
+        b.reset();
+        assert_eq!(b.attempt(), 0);
+        assert!(b.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -1,12 +1,16 @@
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::error::Error as StdError;
 use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tungstenite::{connect, Message};
 use url::Url;
 
-use crate::ref_price::RefPrice;
+use crate::backoff::Backoff;
+use crate::ref_price::{Book, BookSide, RefPrice};
+use crate::ws_util;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Request {
@@ -15,71 +19,203 @@ struct Request {
     params: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Response {
-    pub e: String,
-    pub a: String,
-    pub b: String,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BinanceStream {
+    // 24hr rolling ticker, updates at most once per second (spot only has bid/ask in it)
+    Ticker,
+    // real time best bid / ask
+    BookTicker,
+    // partial book depth with 5, 10 or 20 levels
+    Depth5,
+    Depth10,
+    Depth20,
+}
+
+impl Default for BinanceStream {
+    fn default() -> Self {
+        BinanceStream::BookTicker
+    }
+}
+
+impl BinanceStream {
+    // update_ms only applies to the depth streams, e.g. btcusdt@depth5@100ms
+    pub fn stream_name(&self, mkt: &str, update_ms: Option<u64>) -> String {
+        let mkt = mkt.to_lowercase();
+        let depth = match self {
+            BinanceStream::Ticker => return format!("{}@ticker", mkt),
+            BinanceStream::BookTicker => return format!("{}@bookTicker", mkt),
+            BinanceStream::Depth5 => 5,
+            BinanceStream::Depth10 => 10,
+            BinanceStream::Depth20 => 20,
+        };
+        return match update_ms {
+            Some(ms) => format!("{}@depth{}@{}ms", mkt, depth, ms),
+            None => format!("{}@depth{}", mkt, depth),
+        };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Update {
+    Top {
+        bid: f64,
+        bid_size: f64,
+        ask: f64,
+        ask_size: f64,
+        ts: u64,
+    },
+    Depth {
+        book: Book,
+        ts: u64,
+    },
 }
 
-pub async fn start(ws_url: String, mkt: String, rp: Arc<Mutex<RefPrice>>) -> Result<(), Error> {
+// Keeps the binance feed alive forever, only an unparsable url is fatal.
+// Works with spot (stream.binance.com) and USD-M futures (fstream.binance.com).
+pub fn start(
+    ws_url: String,
+    mkt: String,
+    stream: BinanceStream,
+    update_ms: Option<u64>,
+    rp: Arc<Mutex<RefPrice>>,
+) -> Result<(), Error> {
     let url = ws_url.parse::<Url>()?;
+    let stream_name = stream.stream_name(&mkt, update_ms);
+    ws_util::reconnect_forever("binance", |backoff| run(&url, &stream_name, rp.clone(), backoff));
+    return Ok(());
+}
+
+// binance pings us every few minutes and tungstenite answers on its own; our
+// own pings make sure a half open connection errors out instead of blocking
+// the read forever
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const MAX_SILENCE: Duration = Duration::from_secs(60);
+
+fn run(url: &Url, stream_name: &str, rp: Arc<Mutex<RefPrice>>, backoff: &mut Backoff) -> Result<(), Error> {
     info!("opening websocket with binance API at: {}", url);
-    let (mut socket, _) = connect(url)?;
+    let (mut socket, _) = connect(url.clone())?;
     info!("connected to binance successfully");
+    ws_util::set_read_timeout(&mut socket, Some(Duration::from_secs(5))).map_err(tungstenite::Error::Io)?;
 
     let request = serde_json::to_string(&Request {
         id: 1,
         method: "SUBSCRIBE".to_string(),
-        params: vec![format!("{}@ticker", mkt.to_lowercase())],
+        params: vec![stream_name.to_string()],
     })?;
 
     socket.write_message(Message::Text(request))?;
+    info!("subscribed to binance stream {}", stream_name);
 
-    // discard first message, it's confirmation from binance
-    socket.read_message()?;
+    let mut last_ping = Instant::now();
+    let mut last_msg = Instant::now();
     loop {
-        let msg = socket.read_message()?;
-        match serde_json::from_str::<Response>(&msg.to_string()) {
-            Ok(r) => {
-                if r.e == "24hrTicker" {
-                    //info!("new binance prices: {:?}", r);
-                    rp.lock()
-                        .unwrap()
-                        .set(r.b.parse::<f64>().unwrap(), r.a.parse::<f64>().unwrap());
-                }
+        if last_ping.elapsed() >= PING_INTERVAL {
+            socket.write_message(Message::Ping(vec![]))?;
+            last_ping = Instant::now();
+        }
+        if last_msg.elapsed() >= MAX_SILENCE {
+            return Err(Error::from(ws_util::timeout_error("no message from binance, not even a pong")));
+        }
+
+        let msg = match socket.read_message() {
+            Ok(m) => m,
+            Err(e) if ws_util::is_timeout(&e) => continue,
+            Err(e) => return Err(Error::from(e)),
+        };
+        last_msg = Instant::now();
+
+        let text = match msg {
+            Message::Text(t) => t,
+            Message::Close(frame) => {
+                info!("binance sent close frame: {:?}", frame);
+                return Ok(());
             }
             _ => continue,
+        };
+
+        // the subscription confirmation and anything else we don't understand is skipped
+        match parse_message(&text) {
+            Some(Update::Top { bid, bid_size, ask, ask_size, ts }) => {
+                rp.lock().unwrap().set_top(bid, bid_size, ask, ask_size, ts);
+            }
+            Some(Update::Depth { book, ts }) => {
+                if !rp.lock().unwrap().set_book(book, ts) {
+                    warn!("skipping binance depth update with an empty side");
+                    continue;
+                }
+            }
+            None => continue,
         }
+        backoff.reset();
+    }
+}
+
+fn value_to_f64(v: &Value) -> Option<f64> {
+    return v.as_str()?.parse::<f64>().ok();
+}
+
+fn parse_levels(levels: &Value, side: BookSide, book: &mut Book) -> Option<()> {
+    for level in levels.as_array()?.iter() {
+        let price = value_to_f64(level.get(0)?)?;
+        let size = value_to_f64(level.get(1)?)?;
+        book.set_level(side, price, size);
+    }
+    return Some(());
+}
+
+// Handles the spot and futures flavours of the ticker, bookTicker and
+// partial depth payloads. Spot partial depth uses bids/asks while futures
+// uses b/a arrays; tickers carry b/B/a/A as strings.
+fn parse_message(text: &str) -> Option<Update> {
+    let v: Value = serde_json::from_str(text).ok()?;
+    // futures send the event time, spot bookTicker and partial depth don't
+    let ts = v.get("E").and_then(|t| t.as_u64()).unwrap_or(0);
+
+    let bids = v.get("bids").or_else(|| v.get("b").filter(|b| b.is_array()));
+    let asks = v.get("asks").or_else(|| v.get("a").filter(|a| a.is_array()));
+    if let (Some(bids), Some(asks)) = (bids, asks) {
+        let mut book = Book::new();
+        parse_levels(bids, BookSide::Bid, &mut book)?;
+        parse_levels(asks, BookSide::Ask, &mut book)?;
+        return Some(Update::Depth { book, ts });
     }
+
+    let bid = value_to_f64(v.get("b")?)?;
+    let ask = value_to_f64(v.get("a")?)?;
+    let bid_size = v.get("B").and_then(value_to_f64).unwrap_or(0.0);
+    let ask_size = v.get("A").and_then(value_to_f64).unwrap_or(0.0);
+    return Some(Update::Top { bid, bid_size, ask, ask_size, ts });
 }
 
 #[derive(Debug)]
 pub enum Error {
-    WSError,
+    WSError(tungstenite::Error),
+    UrlError(url::ParseError),
+    JsonError(serde_json::Error),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "wallet client error: {}", self.desc())
+        write!(f, "binance feed error: {}", self.desc())
     }
 }
 
 impl From<tungstenite::Error> for Error {
-    fn from(_: tungstenite::Error) -> Self {
-        Error::WSError
+    fn from(error: tungstenite::Error) -> Self {
+        Error::WSError(error)
     }
 }
 
 impl From<url::ParseError> for Error {
-    fn from(_: url::ParseError) -> Self {
-        Error::WSError
+    fn from(error: url::ParseError) -> Self {
+        Error::UrlError(error)
     }
 }
 
 impl From<serde_json::Error> for Error {
-    fn from(_: serde_json::Error) -> Self {
-        Error::WSError
+    fn from(error: serde_json::Error) -> Self {
+        Error::JsonError(error)
     }
 }
 
@@ -89,7 +225,65 @@ impl Error {
     pub fn desc(&self) -> String {
         use Error::*;
         match self {
-            WSError => format!("websocket error"),
+            WSError(e) => format!("websocket error: {}", e),
+            UrlError(e) => format!("invalid url: {}", e),
+            JsonError(e) => format!("json error: {}", e),
+        }
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_name() {
+        assert_eq!(BinanceStream::BookTicker.stream_name("BTCUSDT", None), "btcusdt@bookTicker");
+        assert_eq!(BinanceStream::Depth5.stream_name("BTCUSDT", Some(100)), "btcusdt@depth5@100ms");
+        assert_eq!(BinanceStream::Depth20.stream_name("BTCUSDT", None), "btcusdt@depth20");
+    }
+
+    #[test]
+    fn test_parse_book_ticker() {
+        let spot = r#"{"u":400900217,"s":"BNBUSDT","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#;
+        assert_eq!(
+            parse_message(spot),
+            Some(Update::Top { bid: 25.3519, bid_size: 31.21, ask: 25.3652, ask_size: 40.66, ts: 0 })
+        );
+
+        let futures = r#"{"e":"bookTicker","u":400900217,"E":1568014460893,"T":1568014460891,"s":"BNBUSDT","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#;
+        assert_eq!(
+            parse_message(futures),
+            Some(Update::Top { bid: 25.3519, bid_size: 31.21, ask: 25.3652, ask_size: 40.66, ts: 1568014460893 })
+        );
+    }
+
+    #[test]
+    fn test_parse_depth() {
+        let spot = r#"{"lastUpdateId":160,"bids":[["0.0024","10"],["0.0023","5"]],"asks":[["0.0026","100"]]}"#;
+        match parse_message(spot) {
+            Some(Update::Depth { book, ts }) => {
+                assert_eq!(ts, 0);
+                assert_eq!(book.bids(), &vec![(0.0024, 10.0), (0.0023, 5.0)]);
+                assert_eq!(book.asks(), &vec![(0.0026, 100.0)]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let futures = r#"{"e":"depthUpdate","E":1571889248277,"T":1571889248276,"s":"BTCUSDT","U":390497796,"u":390497878,"pu":390497794,"b":[["7403.89","0.002"]],"a":[["7405.96","3.340"],["7406.63","4.525"]]}"#;
+        match parse_message(futures) {
+            Some(Update::Depth { book, ts }) => {
+                assert_eq!(ts, 1571889248277);
+                assert_eq!(book.best_bid(), Some((7403.89, 0.002)));
+                assert_eq!(book.asks().len(), 2);
+            }
+            other => panic!("unexpected {:?}", other),
         }
     }
+
+    #[test]
+    fn test_parse_skips_ack() {
+        assert_eq!(parse_message(r#"{"result":null,"id":1}"#), None);
+        assert_eq!(parse_message("garbage"), None);
+    }
 }
@@ -1,11 +1,54 @@
-use log::{error, info};
+use log::{error, info, warn};
 use reqwest::Error;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::time::{sleep, Duration};
 use chrono::{NaiveDateTime, Utc, TimeZone};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tungstenite::{connect, Message};
 
-use crate::ref_price::RefPrice;
+use crate::backoff::Backoff;
+use crate::ref_price::{Book, BookSide, RefPrice};
+use crate::ws_util;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BybitCategory {
+    Spot,
+    Linear,
+    Inverse,
+}
+
+impl BybitCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BybitCategory::Spot => "spot",
+            BybitCategory::Linear => "linear",
+            BybitCategory::Inverse => "inverse",
+        }
+    }
+}
+
+impl Default for BybitCategory {
+    fn default() -> Self {
+        BybitCategory::Spot
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BybitMode {
+    // public websocket orderbook stream
+    Ws,
+    // poll the REST orderbook endpoint
+    Rest,
+}
+
+impl Default for BybitMode {
+    fn default() -> Self {
+        BybitMode::Ws
+    }
+}
 
 #[derive(Deserialize, Debug)]
 struct ApiResponse {
@@ -27,61 +70,198 @@ struct ResultData {
     cts: u64,
 }
 
+#[derive(Deserialize, Debug)]
+struct WsMessage {
+    topic: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    ts: Option<u64>,
+    data: Option<WsBookData>,
+}
 
+#[derive(Deserialize, Debug)]
+struct WsBookData {
+    s: String,
+    a: Vec<[String; 2]>,
+    b: Vec<[String; 2]>,
+}
 
-async fn fetch_order_book(bybit_url: &String, bybit_symbol: &String) -> Result<ApiResponse, reqwest::Error> {
-    let bybit_url_owned = String::from(bybit_url);
-    let url = bybit_url_owned + "/v5/market/orderbook?category=spot&symbol=" + bybit_symbol;
+async fn fetch_order_book(bybit_url: &String, category: BybitCategory, bybit_symbol: &String, depth: u32) -> Result<ApiResponse, reqwest::Error> {
+    let url = format!(
+        "{}/v5/market/orderbook?category={}&symbol={}&limit={}",
+        bybit_url,
+        category.as_str(),
+        bybit_symbol,
+        depth
+    );
     info!("Reading Bybit prices from {}", url);
     //let url = "https://api.bybit.com/v5/market/orderbook?category=spot&symbol=VEGAUSDT";
     let response = reqwest::get(url).await?.json::<ApiResponse>().await?;
     Ok(response)
 }
 
-pub async fn start(bybit_url: String, mkt: String, rp: Arc<Mutex<RefPrice>>, sleep_in_millis: u64)  {
+// Applies bybit [price, size] levels to the book, levels we can't parse are skipped.
+fn apply_levels(book: &mut Book, side: BookSide, levels: &Vec<[String; 2]>, symbol: &str) {
+    for level in levels.iter() {
+        match (level[0].parse::<f64>(), level[1].parse::<f64>()) {
+            (Ok(price), Ok(size)) => book.set_level(side, price, size),
+            _ => warn!("skipping bad bybit {} level: {:?}", symbol, level),
+        }
+    }
+}
+
+// REST polling fallback
+pub async fn start(bybit_url: String, category: BybitCategory, mkt: String, depth: u32, rp: Arc<Mutex<RefPrice>>, sleep_in_millis: u64)  {
     loop {
-        match fetch_order_book(&bybit_url, &mkt).await {
+        match fetch_order_book(&bybit_url, category, &mkt, depth).await {
             Ok(order_book) => {
                 //println!("{:#?}", order_book);
-                let best_ask_str = &order_book.result.a[0][0];
-                let best_bid_str = &order_book.result.b[0][0];
-                
-                // Convert the best bid price to f64
-                let best_ask: f64 = match best_ask_str.parse() {
-                    Ok(val) => val,
-                    Err(e) => {
-                        info!("Error parsing best ask price: {}", e);
-                        return;
-                    }
-                };
-
-                // Convert the best bid price to f64
-                let best_bid: f64 = match best_bid_str.parse() {
-                    Ok(val) => val,
-                    Err(e) => {
-                        info!("Error parsing best bid price: {}", e);
-                        return;
-                    }
-                };
+                let mut book = Book::new();
+                apply_levels(&mut book, BookSide::Bid, &order_book.result.b, &order_book.result.s);
+                apply_levels(&mut book, BookSide::Ask, &order_book.result.a, &order_book.result.s);
+
+                match (book.best_bid(), book.best_ask()) {
+                    (Some((best_bid, _)), Some((best_ask, _))) => {
+                        let mid = 0.5*(best_ask+best_bid);
+                        let spread = best_ask - best_bid;
+                        let spread_in_bp = 10_000.0*(best_ask - best_bid)/mid;
 
-                let mid = 0.5*(best_ask+best_bid);
-                let spread = best_ask - best_bid;
-                let spread_in_bp = 10_000.0*(best_ask - best_bid)/mid;
-                
-                // Convert Unix timestamp to NaiveDateTime
-                let naive_datetime = NaiveDateTime::from_timestamp((order_book.time/1000) as i64, 0);
+                        // Convert Unix timestamp to NaiveDateTime
+                        let naive_datetime = NaiveDateTime::from_timestamp((order_book.time/1000) as i64, 0);
 
-                // Convert NaiveDateTime to DateTime<Utc>
-                let datetime = Utc.from_utc_datetime(&naive_datetime);
+                        // Convert NaiveDateTime to DateTime<Utc>
+                        let datetime = Utc.from_utc_datetime(&naive_datetime);
 
-                info!("Binance best ask {:.4}; best bid {:.4}; spread {:.5} which is {:.1} bp at {}", best_ask, best_bid, spread,spread_in_bp, datetime.to_rfc3339());
+                        info!("Bybit best ask {:.4}; best bid {:.4}; spread {:.5} which is {:.1} bp at {}", best_ask, best_bid, spread,spread_in_bp, datetime.to_rfc3339());
 
-                rp.lock().unwrap().set(best_bid, best_ask);
+                        rp.lock().unwrap().set_book(book, order_book.result.ts);
+                    }
+                    _ => {
+                        warn!("skipping Bybit order book without usable best bid / ask");
+                    }
+                }
             }
             Err(e) => {
-                eprintln!("Error fetching binance order book: {}", e);
+                error!("Error fetching Bybit order book: {}", e);
             }
         }
         sleep(Duration::from_millis(sleep_in_millis)).await;
     }
 }
+
+// Websocket orderbook.<depth> stream, reconnects with backoff forever.
+pub fn start_ws(ws_url: String, category: BybitCategory, mkt: String, depth: u32, rp: Arc<Mutex<RefPrice>>) {
+    let url = format!("{}/{}", ws_url.trim_end_matches('/'), category.as_str());
+    ws_util::reconnect_forever("bybit", |backoff| run_ws(&url, &mkt, depth, rp.clone(), backoff));
+}
+
+// bybit drops connections that don't ping at least every 20 seconds
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const MAX_SILENCE: Duration = Duration::from_secs(60);
+
+fn run_ws(url: &str, mkt: &str, depth: u32, rp: Arc<Mutex<RefPrice>>, backoff: &mut Backoff) -> Result<(), tungstenite::Error> {
+    info!("opening websocket with bybit API at: {}", url);
+    let (mut socket, _) = connect(url)?;
+    info!("connected to bybit successfully");
+    ws_util::set_read_timeout(&mut socket, Some(Duration::from_secs(5)))?;
+
+    let request = serde_json::json!({
+        "op": "subscribe",
+        "args": [format!("orderbook.{}.{}", depth, mkt)],
+    });
+    socket.write_message(Message::Text(request.to_string()))?;
+
+    let mut book = Book::new();
+    let mut last_ping = Instant::now();
+    let mut last_msg = Instant::now();
+    loop {
+        if last_ping.elapsed() >= PING_INTERVAL {
+            socket.write_message(Message::Text(serde_json::json!({"op": "ping"}).to_string()))?;
+            last_ping = Instant::now();
+        }
+        if last_msg.elapsed() >= MAX_SILENCE {
+            return Err(ws_util::timeout_error("no message from bybit, not even a pong"));
+        }
+
+        let msg = match socket.read_message() {
+            Ok(m) => m,
+            Err(e) if ws_util::is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        last_msg = Instant::now();
+
+        let text = match msg {
+            Message::Text(t) => t,
+            Message::Close(frame) => {
+                info!("bybit sent close frame: {:?}", frame);
+                return Ok(());
+            }
+            _ => continue,
+        };
+
+        if apply_ws_message(&text, &mut book, &rp) {
+            backoff.reset();
+        }
+    }
+}
+
+// Returns true if the message updated the reference price. Anything we can't
+// parse is logged and skipped, a bad message shouldn't kill the feed.
+fn apply_ws_message(text: &str, book: &mut Book, rp: &Arc<Mutex<RefPrice>>) -> bool {
+    let msg = match serde_json::from_str::<WsMessage>(text) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("skipping malformed bybit message: {} ({})", text, e);
+            return false;
+        }
+    };
+
+    let data = match (msg.topic.as_ref(), msg.data) {
+        (Some(topic), Some(data)) if topic.starts_with("orderbook.") => data,
+        // subscription acks and pongs
+        _ => return false,
+    };
+
+    if msg.kind.as_deref() == Some("snapshot") {
+        book.clear();
+    }
+    apply_levels(book, BookSide::Bid, &data.b, &data.s);
+    apply_levels(book, BookSide::Ask, &data.a, &data.s);
+
+    return rp.lock().unwrap().set_book(book.clone(), msg.ts.unwrap_or(0));
+}
+
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_ws_snapshot_and_delta() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut book = Book::new();
+
+        let snapshot = r#"{"topic":"orderbook.1.BTCUSDT","type":"snapshot","ts":1672304484978,"data":{"s":"BTCUSDT","b":[["16493.50","0.006"]],"a":[["16611.00","0.029"]],"u":18521288,"seq":7961638724},"cts":1672304484976}"#;
+        assert!(apply_ws_message(snapshot, &mut book, &rp));
+        assert_eq!(rp.lock().unwrap().get(), (16493.5, 16611.0));
+        assert_eq!(rp.lock().unwrap().exchange_ts(), 1672304484978);
+
+        let delta = r#"{"topic":"orderbook.1.BTCUSDT","type":"delta","ts":1672304484988,"data":{"s":"BTCUSDT","b":[["16493.50","0"],["16494.00","0.1"]],"a":[],"u":18521289,"seq":7961638725},"cts":1672304484986}"#;
+        assert!(apply_ws_message(delta, &mut book, &rp));
+        assert_eq!(rp.lock().unwrap().get(), (16494.0, 16611.0));
+        assert_eq!(rp.lock().unwrap().sizes(), (0.1, 0.029));
+    }
+
+    #[test]
+    fn test_apply_ws_skips_junk() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut book = Book::new();
+
+        let pong = r#"{"success":true,"ret_msg":"pong","conn_id":"0970e817-426e-429a-a679-ff7f55e0b16a","op":"ping"}"#;
+        assert!(!apply_ws_message(pong, &mut book, &rp));
+        assert!(!apply_ws_message("not json", &mut book, &rp));
+
+        let bad_level = r#"{"topic":"orderbook.1.BTCUSDT","type":"snapshot","ts":1,"data":{"s":"BTCUSDT","b":[["abc","0.006"]],"a":[["16611.00","0.029"]]}}"#;
+        assert!(!apply_ws_message(bad_level, &mut book, &rp));
+        assert_eq!(rp.lock().unwrap().get(), (0.0, 0.0));
+    }
+}
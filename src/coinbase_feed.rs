@@ -0,0 +1,256 @@
+use chrono::DateTime;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tungstenite::{connect, Message};
+
+use crate::backoff::Backoff;
+use crate::ref_price::{Book, BookSide, RefPrice};
+use crate::ws_util;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoinbaseChannel {
+    // best bid / ask with sizes
+    Ticker,
+    // full order book
+    Level2,
+}
+
+impl Default for CoinbaseChannel {
+    fn default() -> Self {
+        CoinbaseChannel::Ticker
+    }
+}
+
+impl CoinbaseChannel {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CoinbaseChannel::Ticker => "ticker",
+            CoinbaseChannel::Level2 => "level2",
+        }
+    }
+}
+
+// Coinbase Advanced Trade market data feed, reconnects with backoff forever.
+pub fn start(ws_url: String, mkt: String, channel: CoinbaseChannel, rp: Arc<Mutex<RefPrice>>) {
+    ws_util::reconnect_forever("coinbase", |backoff| run(&ws_url, &mkt, channel, rp.clone(), backoff));
+}
+
+// We also subscribe to heartbeats, coinbase closes connections that go quiet,
+// and with heartbeats every second a read timeout means the connection is dead.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// level2 streams the whole book, which we have to keep to apply updates, but
+// pricing only looks near the top so that's all we hand to the ref price.
+const PUBLISHED_DEPTH: usize = 50;
+
+fn run(
+    url: &str,
+    mkt: &str,
+    channel: CoinbaseChannel,
+    rp: Arc<Mutex<RefPrice>>,
+    backoff: &mut Backoff,
+) -> Result<(), tungstenite::Error> {
+    info!("opening websocket with coinbase API at: {}", url);
+    let (mut socket, _) = connect(url)?;
+    info!("connected to coinbase successfully");
+    ws_util::set_read_timeout(&mut socket, Some(READ_TIMEOUT))?;
+
+    for ch in [channel.as_str(), "heartbeats"] {
+        let request = serde_json::json!({
+            "type": "subscribe",
+            "product_ids": [mkt],
+            "channel": ch,
+        });
+        socket.write_message(Message::Text(request.to_string()))?;
+    }
+
+    let mut book = Book::new();
+    loop {
+        let msg = socket.read_message()?;
+        let text = match msg {
+            Message::Text(t) => t,
+            Message::Close(frame) => {
+                info!("coinbase sent close frame: {:?}", frame);
+                return Ok(());
+            }
+            _ => continue,
+        };
+
+        if apply_message(&text, mkt, &mut book, &rp) {
+            backoff.reset();
+        }
+    }
+}
+
+fn value_to_f64(v: Option<&Value>) -> Option<f64> {
+    return v?.as_str()?.parse::<f64>().ok();
+}
+
+fn timestamp_millis(v: &Value) -> u64 {
+    return v
+        .get("timestamp")
+        .and_then(|t| t.as_str())
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.timestamp_millis() as u64)
+        .unwrap_or(0);
+}
+
+// Returns true if the message updated the reference price. Subscription
+// confirmations, heartbeats and anything malformed are skipped.
+fn apply_message(text: &str, mkt: &str, book: &mut Book, rp: &Arc<Mutex<RefPrice>>) -> bool {
+    let v: Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("skipping malformed coinbase message: {} ({})", text, e);
+            return false;
+        }
+    };
+    let ts = timestamp_millis(&v);
+    let events = match v.get("events").and_then(|e| e.as_array()) {
+        Some(e) => e,
+        None => return false,
+    };
+
+    match v.get("channel").and_then(|c| c.as_str()) {
+        Some("ticker") => {
+            let mut updated = false;
+            for event in events.iter() {
+                let tickers = match event.get("tickers").and_then(|t| t.as_array()) {
+                    Some(t) => t,
+                    None => continue,
+                };
+                for t in tickers.iter() {
+                    if t.get("product_id").and_then(|p| p.as_str()) != Some(mkt) {
+                        continue;
+                    }
+                    let top = (
+                        value_to_f64(t.get("best_bid")),
+                        value_to_f64(t.get("best_bid_quantity")),
+                        value_to_f64(t.get("best_ask")),
+                        value_to_f64(t.get("best_ask_quantity")),
+                    );
+                    match top {
+                        (Some(bid), Some(bid_size), Some(ask), Some(ask_size)) if bid > 0.0 && ask > 0.0 => {
+                            rp.lock().unwrap().set_top(bid, bid_size, ask, ask_size, ts);
+                            updated = true;
+                        }
+                        _ => warn!("skipping coinbase ticker without best bid / ask: {}", t),
+                    }
+                }
+            }
+            return updated;
+        }
+        Some("l2_data") => {
+            for event in events.iter() {
+                if event.get("product_id").and_then(|p| p.as_str()) != Some(mkt) {
+                    continue;
+                }
+                if event.get("type").and_then(|t| t.as_str()) == Some("snapshot") {
+                    book.clear();
+                }
+                let updates = match event.get("updates").and_then(|u| u.as_array()) {
+                    Some(u) => u,
+                    None => continue,
+                };
+                for u in updates.iter() {
+                    let side = match u.get("side").and_then(|s| s.as_str()) {
+                        Some("bid") => BookSide::Bid,
+                        Some("offer") | Some("ask") => BookSide::Ask,
+                        _ => {
+                            warn!("skipping coinbase level with unknown side: {}", u);
+                            continue;
+                        }
+                    };
+                    match (value_to_f64(u.get("price_level")), value_to_f64(u.get("new_quantity"))) {
+                        (Some(price), Some(size)) => book.set_level(side, price, size),
+                        _ => warn!("skipping bad coinbase level: {}", u),
+                    }
+                }
+            }
+            let top = book.top(PUBLISHED_DEPTH);
+            return rp.lock().unwrap().set_book(top, ts);
+        }
+        _ => return false,
+    }
+}
+
+
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // recorded from the advanced trade feed, trimmed
+    const TICKER_MESSAGES: [&str; 3] = [
+        r#"{"channel":"subscriptions","client_id":"","timestamp":"2024-05-02T10:00:00.000000001Z","sequence_num":0,"events":[{"subscriptions":{"ticker":["BTC-USD"]}}]}"#,
+        r#"{"channel":"ticker","client_id":"","timestamp":"2024-05-02T10:00:00.123456789Z","sequence_num":1,"events":[{"type":"snapshot","tickers":[{"type":"ticker","product_id":"BTC-USD","price":"59000.01","volume_24_h":"16038.28","low_24_h":"58000","high_24_h":"60000","low_52_w":"25000","high_52_w":"73000","price_percent_chg_24_h":"1.2","best_bid":"59000.00","best_bid_quantity":"0.5","best_ask":"59000.02","best_ask_quantity":"1.5"}]}]}"#,
+        r#"{"channel":"heartbeats","client_id":"","timestamp":"2024-05-02T10:00:01.000000000Z","sequence_num":2,"events":[{"current_time":"2024-05-02 10:00:01 +0000 UTC","heartbeat_counter":"1"}]}"#,
+    ];
+
+    const L2_MESSAGES: [&str; 2] = [
+        r#"{"channel":"l2_data","client_id":"","timestamp":"2024-05-02T10:00:00.5Z","sequence_num":0,"events":[{"type":"snapshot","product_id":"BTC-USD","updates":[{"side":"bid","event_time":"2024-05-02T10:00:00.5Z","price_level":"59000.00","new_quantity":"0.5"},{"side":"bid","event_time":"2024-05-02T10:00:00.5Z","price_level":"58999.00","new_quantity":"2"},{"side":"offer","event_time":"2024-05-02T10:00:00.5Z","price_level":"59001.00","new_quantity":"1"}]}]}"#,
+        r#"{"channel":"l2_data","client_id":"","timestamp":"2024-05-02T10:00:00.6Z","sequence_num":1,"events":[{"type":"update","product_id":"BTC-USD","updates":[{"side":"bid","event_time":"2024-05-02T10:00:00.6Z","price_level":"59000.00","new_quantity":"0"},{"side":"offer","event_time":"2024-05-02T10:00:00.6Z","price_level":"59000.50","new_quantity":"3"}]}]}"#,
+    ];
+
+    // Local stand-in for the coinbase websocket: waits for our two
+    // subscriptions, replays the messages and closes the connection.
+    fn replay_server(messages: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(stream).unwrap();
+            for _ in 0..2 {
+                ws.read_message().unwrap();
+            }
+            for m in messages.into_iter() {
+                ws.write_message(Message::Text(m.to_string())).unwrap();
+            }
+            ws.close(None).unwrap();
+            // drain until the client acknowledges the close
+            while ws.read_message().is_ok() {}
+        });
+        return format!("ws://{}", addr);
+    }
+
+    #[test]
+    fn test_ticker_replay() {
+        let url = replay_server(TICKER_MESSAGES.to_vec());
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        run(&url, "BTC-USD", CoinbaseChannel::Ticker, rp.clone(), &mut backoff).unwrap();
+
+        let rp = rp.lock().unwrap();
+        assert_eq!(rp.get(), (59000.0, 59000.02));
+        assert_eq!(rp.sizes(), (0.5, 1.5));
+        assert_eq!(rp.exchange_ts(), 1714644000123);
+    }
+
+    #[test]
+    fn test_level2_replay() {
+        let url = replay_server(L2_MESSAGES.to_vec());
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        run(&url, "BTC-USD", CoinbaseChannel::Level2, rp.clone(), &mut backoff).unwrap();
+
+        let rp = rp.lock().unwrap();
+        assert_eq!(rp.get(), (58999.0, 59000.5));
+        assert_eq!(rp.book().asks(), &vec![(59000.5, 3.0), (59001.0, 1.0)]);
+    }
+
+    #[test]
+    fn test_skips_other_products_and_junk() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut book = Book::new();
+        assert!(!apply_message("not json", "BTC-USD", &mut book, &rp));
+        assert!(!apply_message(TICKER_MESSAGES[1], "ETH-USD", &mut book, &rp));
+        assert!(!apply_message(TICKER_MESSAGES[2], "BTC-USD", &mut book, &rp));
+        assert_eq!(rp.lock().unwrap().get(), (0.0, 0.0));
+    }
+}
@@ -1,9 +1,11 @@
 use log::info;
 use vega_protobufs::vega::Trade;
 use vega_protobufs::vega::trade;
+use vega_protobufs::vega::Side;
 
 use crate::vega_store2::VegaStore;
 use crate::vega_store2::TradeStat;
+use crate::fills::Fill;
 
 
 // We are assuming aggresive orders (and thus trades occuring) happens according to a Poisson distribution
@@ -114,6 +116,44 @@ pub fn estimate_kappa(initial_kappa: f64,
 
 }
 
+// Same estimator, but on how far from the mid our own resting orders got
+// filled, which is what kappa describes in the first place. Taker fills and
+// fills we have no book for don't count. None with fewer than min_fills
+// fills to go on.
+pub fn estimate_kappa_from_fills(initial_kappa: f64,
+    kappa_weight: f64,
+    current_t: u64, // current time
+    estimation_interval: u64, // period over which to consider fills
+    fills: &Vec<Fill>,
+    price_factor: f64,
+    min_fills: u64) -> Option<f64> {
+
+    let mut sum_mid_price_diffs = 0 as f64;
+    let mut fill_count = 0 as u64;
+    for f in fills.iter().rev() {
+        if current_t.saturating_sub(f.timestamp) > estimation_interval {
+            break;
+        }
+        if !f.is_maker || f.block_best_bid <= 0.0 || f.block_best_ask <= 0.0 {
+            continue;
+        }
+        let mid = (f.block_best_ask + f.block_best_bid) / 2.0;
+        sum_mid_price_diffs += f64::abs(f.price - mid) / price_factor;
+        fill_count += 1;
+    }
+    if fill_count == 0 || fill_count < min_fills {
+        return None;
+    }
+
+    let kappa_mle = (fill_count as f64) / sum_mid_price_diffs;
+    if kappa_mle.is_nan() || kappa_mle.is_infinite() {
+        info!("kappa mle estimate from fills is NaN or Inf. ");
+        return Some(initial_kappa);
+    }
+
+    return Some(kappa_weight * initial_kappa + (1.0-kappa_weight) * kappa_mle);
+}
+
 
 pub enum BuySellUnsure {
     Buy, 
@@ -122,6 +162,14 @@ pub enum BuySellUnsure {
 }
 
 pub fn trade_is_buy(t: TradeStat) -> BuySellUnsure {
+    // the aggressor side is exact, comparing with the book is only a guess
+    // for trades without one (auction uncrossing)
+    match t.aggressor {
+        Side::Buy => return BuySellUnsure::Buy,
+        Side::Sell => return BuySellUnsure::Sell,
+        _ => {}
+    }
+
     let mut buy_sell_unsure: BuySellUnsure = BuySellUnsure::Unsure;
     if t.price >= t.block_best_ask && t.price >= t.block_best_bid {
         buy_sell_unsure = BuySellUnsure::Buy;
@@ -147,6 +195,10 @@ mod tests {
                 size: 1,
                 block_best_bid: 99.0,
                 block_best_ask: 101.0,
+                aggressor: Side::Sell,
+                buyer: "buyer".to_string(),
+                seller: "seller".to_string(),
+                is_ours: false,
             };
             trades_vec.push(new_trade)
         }
@@ -175,4 +227,41 @@ mod tests {
         assert_eq!(lambd, 1.0 as f64);
     }
 
+    #[test]
+    fn test_estimate_kappa_from_fills() {
+        let fill = |price: f64, is_maker: bool| Fill {
+            trade_id: "t".to_string(),
+            order_id: "o".to_string(),
+            timestamp: 100,
+            side: Side::Buy,
+            price,
+            size: 1,
+            fee: 0.0,
+            is_maker,
+            block_best_bid: 99.0,
+            block_best_ask: 101.0,
+            seq: 0,
+        };
+        assert_eq!(estimate_kappa_from_fills(0.5, 0.0, 100, 10, &vec![], 1.0, 0), None);
+        // mean distance 2, the taker fill is ignored
+        let fills = vec![fill(99.0, true), fill(103.0, true), fill(110.0, false)];
+        assert_eq!(estimate_kappa_from_fills(1.0, 0.0, 100, 10, &fills, 1.0, 2), Some(0.5));
+        assert_eq!(estimate_kappa_from_fills(1.0, 0.5, 100, 10, &fills, 0.5, 2), Some(0.5 + 0.5 * 0.25));
+        // not enough maker fills yet
+        assert_eq!(estimate_kappa_from_fills(1.0, 0.0, 100, 10, &fills, 1.0, 3), None);
+    }
+
+    #[test]
+    fn test_trade_is_buy_uses_aggressor() {
+        let mut t = generate_trades_unif(0, 60, 2)[0].clone();
+        // mid spread, the book can't tell
+        t.price = 100.0;
+        t.aggressor = Side::Buy;
+        assert!(matches!(trade_is_buy(t.clone()), BuySellUnsure::Buy));
+        t.aggressor = Side::Unspecified;
+        assert!(matches!(trade_is_buy(t.clone()), BuySellUnsure::Unsure));
+        t.price = 98.0;
+        assert!(matches!(trade_is_buy(t), BuySellUnsure::Sell));
+    }
+
 }
\ No newline at end of file
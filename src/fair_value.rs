@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ref_price::RefPrice;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FairValueMode {
+    // plain (ask + bid) / 2
+    Mid,
+    // mid shifted by the best bid / ask size imbalance
+    Microprice,
+    // mid shifted by the size imbalance over the first few levels of depth
+    Imbalance,
+}
+
+impl Default for FairValueMode {
+    fn default() -> Self {
+        FairValueMode::Mid
+    }
+}
+
+// Order book imbalance in [-1, 1], positive when there's more size on the bid.
+// Microprice uses the top of book sizes, Imbalance sums up to `depth` levels
+// and falls back to the top of book for sources that don't stream depth.
+// None if we have no size information at all.
+pub fn book_imbalance(rp: &RefPrice, mode: FairValueMode, depth: usize) -> Option<f64> {
+    if mode == FairValueMode::Mid {
+        return None;
+    }
+    let (mut bid_size, mut ask_size) = rp.sizes();
+    if mode == FairValueMode::Imbalance && !rp.book().bids().is_empty() && !rp.book().asks().is_empty() {
+        bid_size = rp.book().bids().iter().take(depth).map(|l| l.1).sum();
+        ask_size = rp.book().asks().iter().take(depth).map(|l| l.1).sum();
+    }
+
+    return size_imbalance(bid_size, ask_size);
+}
+
+pub fn size_imbalance(bid_size: f64, ask_size: f64) -> Option<f64> {
+    if bid_size + ask_size <= 0.0 {
+        return None;
+    }
+    return Some((bid_size - ask_size) / (bid_size + ask_size));
+}
+
+// Weighted average of (weight, imbalance) pairs, one per source with size
+// information. If every weight is zero we fall back to equal weights.
+pub fn combined_imbalance(with_imbalance: &Vec<(f64, f64)>) -> Option<f64> {
+    if with_imbalance.is_empty() {
+        return None;
+    }
+
+    let total_weight: f64 = with_imbalance.iter().map(|(w, _)| w).sum();
+    if total_weight <= 0.0 {
+        let n = with_imbalance.len() as f64;
+        return Some(with_imbalance.iter().map(|(_, i)| i).sum::<f64>() / n);
+    }
+    return Some(with_imbalance.iter().map(|(w, i)| w * i).sum::<f64>() / total_weight);
+}
+
+// The microprice bid * ask_size / (bid_size + ask_size) + ask * bid_size / (bid_size + ask_size)
+// is the same as mid + imbalance * half spread; weight scales how far we move.
+pub fn fair_value(bid: f64, ask: f64, imbalance: f64, weight: f64) -> f64 {
+    let mid = 0.5 * (bid + ask);
+    return mid + weight * imbalance * 0.5 * (ask - bid);
+}
+
+
+mod tests {
+    use super::*;
+    use crate::ref_price::{Book, BookSide};
+
+    #[test]
+    fn test_microprice() {
+        let mut rp = RefPrice::new();
+        rp.set_top(100.0, 3.0, 102.0, 1.0, 0);
+        let imbalance = book_imbalance(&rp, FairValueMode::Microprice, 5).unwrap();
+        assert_eq!(imbalance, 0.5);
+        // 100 * 1/4 + 102 * 3/4
+        assert_eq!(fair_value(100.0, 102.0, imbalance, 1.0), 101.5);
+        assert_eq!(book_imbalance(&rp, FairValueMode::Mid, 5), None);
+    }
+
+    #[test]
+    fn test_depth_imbalance() {
+        let mut book = Book::new();
+        book.set_level(BookSide::Bid, 100.0, 1.0);
+        book.set_level(BookSide::Bid, 99.0, 5.0);
+        book.set_level(BookSide::Bid, 98.0, 10.0);
+        book.set_level(BookSide::Ask, 101.0, 1.0);
+        book.set_level(BookSide::Ask, 102.0, 1.0);
+        let mut rp = RefPrice::new();
+        rp.set_book(book, 0);
+
+        assert_eq!(book_imbalance(&rp, FairValueMode::Microprice, 2), Some(0.0));
+        assert_eq!(book_imbalance(&rp, FairValueMode::Imbalance, 2), Some(0.5));
+    }
+
+    #[test]
+    fn test_combined_imbalance() {
+        assert_eq!(combined_imbalance(&vec![]), None);
+        assert_eq!(combined_imbalance(&vec![(3.0, 0.5), (1.0, -0.5)]), Some(0.25));
+        assert_eq!(combined_imbalance(&vec![(0.0, 0.5), (0.0, -0.25)]), Some(0.125));
+        assert_eq!(size_imbalance(3.0, 1.0), Some(0.5));
+        assert_eq!(size_imbalance(0.0, 0.0), None);
+    }
+}
@@ -0,0 +1,182 @@
+use vega_protobufs::vega::{Fee, Side, Trade};
+
+// One of our fills, prices in market decimals, sizes in position decimals
+// and fees in asset decimals like the Trade it came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fill {
+    pub trade_id: String,
+    pub order_id: String,
+    pub timestamp: u64,
+    pub side: Side,
+    pub price: f64,
+    pub size: u64,
+    // positive if we paid it, negative if we received it (maker fee)
+    pub fee: f64,
+    // our order was resting on the book
+    pub is_maker: bool,
+    // book when the fill happened, 0 until that block's market data is in
+    pub block_best_bid: f64,
+    pub block_best_ask: f64,
+    // order the store received the fills in, 0 until it has this one
+    pub seq: u64,
+}
+
+fn fee_total(fee: &Option<Fee>) -> f64 {
+    let f = match fee {
+        Some(f) => f,
+        None => return 0.0,
+    };
+    return [&f.maker_fee, &f.infrastructure_fee, &f.liquidity_fee]
+        .iter()
+        .map(|v| v.parse::<f64>().unwrap_or_default())
+        .sum();
+}
+
+fn maker_fee(fee: &Option<Fee>) -> f64 {
+    return fee.as_ref().map(|f| f.maker_fee.parse::<f64>().unwrap_or_default()).unwrap_or_default();
+}
+
+// Our side(s) of a trade: none if we're not in it, two if we traded with
+// ourselves. The aggressor pays all the fees and the maker fee goes to the
+// passive side; in auctions both sides pay half and nobody is the maker.
+pub fn fills_from_trade(trade: &Trade, party_id: &str, block_best_bid: f64, block_best_ask: f64) -> Vec<Fill> {
+    let price = match trade.price.parse::<f64>() {
+        Ok(p) => p,
+        Err(_) => return vec![],
+    };
+    let aggressor = Side::try_from(trade.aggressor).unwrap_or(Side::Unspecified);
+
+    let mut fills = vec![];
+    for side in [Side::Buy, Side::Sell] {
+        let (party, order_id, own_fee, other_fee) = match side {
+            Side::Buy => (&trade.buyer, &trade.buy_order, &trade.buyer_fee, &trade.seller_fee),
+            _ => (&trade.seller, &trade.sell_order, &trade.seller_fee, &trade.buyer_fee),
+        };
+        if party != party_id {
+            continue;
+        }
+        let is_maker = aggressor != Side::Unspecified && aggressor != side;
+        let mut fee = fee_total(own_fee);
+        if is_maker {
+            fee -= maker_fee(other_fee);
+        }
+        fills.push(Fill {
+            trade_id: trade.id.clone(),
+            order_id: order_id.clone(),
+            timestamp: trade.timestamp as u64,
+            side,
+            price,
+            size: trade.size,
+            fee,
+            is_maker,
+            block_best_bid,
+            block_best_ask,
+            seq: 0,
+        });
+    }
+    return fills;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FillStats {
+    pub count: u64,
+    pub maker_count: u64,
+    pub buy_volume: u64,
+    pub sell_volume: u64,
+    // net of maker fees received, in asset decimals
+    pub fees: f64,
+    pub fills_per_minute: f64,
+}
+
+// stats of the fills in the `interval` nanos up to `current_t`
+pub fn fill_stats(fills: &Vec<Fill>, current_t: u64, interval: u64) -> FillStats {
+    let mut stats = FillStats::default();
+    for f in fills.iter().rev() {
+        if current_t.saturating_sub(f.timestamp) > interval {
+            break;
+        }
+        stats.count += 1;
+        if f.is_maker {
+            stats.maker_count += 1;
+        }
+        match f.side {
+            Side::Buy => stats.buy_volume += f.size,
+            _ => stats.sell_volume += f.size,
+        }
+        stats.fees += f.fee;
+    }
+    const ONE_MINUTE: u64 = 60 * 1_000_000_000;
+    if interval > 0 {
+        stats.fills_per_minute = stats.count as f64 / (interval as f64 / ONE_MINUTE as f64);
+    }
+    return stats;
+}
+
+
+mod tests {
+    use super::*;
+
+    fn fee(maker: &str, infra: &str, liquidity: &str) -> Option<Fee> {
+        return Some(Fee {
+            maker_fee: maker.to_string(),
+            infrastructure_fee: infra.to_string(),
+            liquidity_fee: liquidity.to_string(),
+            ..Default::default()
+        });
+    }
+
+    fn trade(buyer: &str, seller: &str, aggressor: Side) -> Trade {
+        return Trade {
+            id: "t".to_string(),
+            price: "1000".to_string(),
+            size: 5,
+            buyer: buyer.to_string(),
+            seller: seller.to_string(),
+            buy_order: "b".to_string(),
+            sell_order: "s".to_string(),
+            aggressor: aggressor.into(),
+            buyer_fee: fee("10", "5", "20"),
+            seller_fee: fee("0", "0", "0"),
+            timestamp: 7,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_fills_from_trade() {
+        assert!(fills_from_trade(&trade("x", "y", Side::Buy), "me", 0.0, 0.0).is_empty());
+
+        // our resting sell got lifted, we receive the maker fee
+        let fills = fills_from_trade(&trade("x", "me", Side::Buy), "me", 990.0, 1010.0);
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].side, Side::Sell);
+        assert_eq!(fills[0].order_id, "s");
+        assert!(fills[0].is_maker);
+        assert_eq!(fills[0].fee, -10.0);
+
+        // we crossed the spread and pay everything
+        let fills = fills_from_trade(&trade("me", "y", Side::Buy), "me", 0.0, 0.0);
+        assert!(!fills[0].is_maker);
+        assert_eq!(fills[0].fee, 35.0);
+
+        assert_eq!(fills_from_trade(&trade("me", "me", Side::Buy), "me", 0.0, 0.0).len(), 2);
+    }
+
+    #[test]
+    fn test_fill_stats() {
+        let mut fills = fills_from_trade(&trade("x", "me", Side::Buy), "me", 0.0, 0.0);
+        fills.extend(fills_from_trade(&trade("me", "y", Side::Buy), "me", 0.0, 0.0));
+        fills[1].timestamp = 60_000_000_000;
+
+        let stats = fill_stats(&fills, 120_000_000_000, 120_000_000_000);
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.maker_count, 1);
+        assert_eq!(stats.buy_volume, 5);
+        assert_eq!(stats.sell_volume, 5);
+        assert_eq!(stats.fees, 25.0);
+        assert_eq!(stats.fills_per_minute, 1.0);
+
+        // only the last one is in the window
+        assert_eq!(fill_stats(&fills, 120_000_000_000, 60_000_000_000).count, 1);
+    }
+}
@@ -0,0 +1,382 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use vega_crypto::Transact;
+use vega_protobufs::vega::{
+    commands::v1::{input_data::Command, BatchMarketInstructions, OrderCancellation, OrderSubmission},
+    order::{TimeInForce, Type},
+    Order, PeggedOrder, PeggedReference, Side,
+};
+
+use crate::fills::Fill;
+use crate::strategy2::{get_asset, Decimals, MarketAsset};
+use crate::vega_store2::VegaStore;
+use crate::Config;
+
+// set on all our flatten orders, it tells them apart from the ladder
+const FLATTEN_REFERENCE: &str = "mm-flatten";
+
+// how often we look at the orders stream while waiting for a cancellation
+const CANCEL_POLL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlattenMode {
+    // reduce only IOC limit orders at the reference price +/- the max slippage
+    Aggressive,
+    // passive orders pegged a tick inside the mid, replaced every slice
+    Pegged,
+}
+
+impl Default for FlattenMode {
+    fn default() -> Self {
+        FlattenMode::Aggressive
+    }
+}
+
+// One child order closing up to `slice` of `open_volume`, less what the
+// live flatten orders (`in_flight`) would already close. None if there is
+// nothing left to close. Prices are in market decimals and sizes in position
+// decimals; a slice of 0 closes everything at once.
+pub fn child_order(
+    market_id: &str,
+    mode: FlattenMode,
+    open_volume: i64,
+    in_flight: u64,
+    slice: u64,
+    reference: f64,
+    max_slippage_bp: f64,
+    tick: u64,
+    expires_at: i64,
+) -> Option<OrderSubmission> {
+    let side = if open_volume > 0 { Side::Sell } else { Side::Buy };
+    let mut size = open_volume.unsigned_abs().saturating_sub(in_flight);
+    if size == 0 {
+        return None;
+    }
+    if slice > 0 {
+        size = size.min(slice);
+    }
+    let tick = tick.max(1);
+
+    let order = match mode {
+        FlattenMode::Aggressive => {
+            // round towards the reference so we never go past the max slippage
+            let slippage = reference * max_slippage_bp / 10_000.0;
+            let price = match side {
+                Side::Buy => ((reference + slippage) / tick as f64).floor() as u64 * tick,
+                _ => ((reference - slippage) / tick as f64).ceil() as u64 * tick,
+            };
+            OrderSubmission {
+                expires_at: 0,
+                market_id: market_id.to_string(),
+                pegged_order: None,
+                price: price.to_string(),
+                size,
+                reference: FLATTEN_REFERENCE.to_string(),
+                side: side.into(),
+                time_in_force: TimeInForce::Ioc.into(),
+                r#type: Type::Limit.into(),
+                reduce_only: true,
+                post_only: false,
+                iceberg_opts: None,
+            }
+        }
+        // vega only allows reduce only on IOC / FOK orders, the size cap is
+        // what keeps these from flipping the position
+        FlattenMode::Pegged => OrderSubmission {
+            expires_at,
+            market_id: market_id.to_string(),
+            pegged_order: Some(PeggedOrder {
+                reference: PeggedReference::Mid.into(),
+                offset: tick.to_string(),
+            }),
+            price: "".to_string(),
+            size,
+            reference: FLATTEN_REFERENCE.to_string(),
+            side: side.into(),
+            time_in_force: TimeInForce::Gtt.into(),
+            r#type: Type::Limit.into(),
+            reduce_only: false,
+            post_only: false,
+            iceberg_opts: None,
+        },
+    };
+    return Some(order);
+}
+
+// Our open volume including the fills the positions stream hasn't caught up
+// with yet, those timestamped after the position was last updated (vega time,
+// nanos). The orders stream drops a filled slice before the position moves,
+// sizing off the position alone would send that volume again.
+pub fn unreported_open_volume(open_volume: i64, position_updated_at: i64, fills: &Vec<Fill>) -> i64 {
+    let mut volume = open_volume;
+    for f in fills.iter().filter(|f| f.timestamp as i64 > position_updated_at) {
+        match f.side {
+            Side::Buy => volume += f.size as i64,
+            _ => volume -= f.size as i64,
+        }
+    }
+    return volume;
+}
+
+// Mark price in market decimals, the mid of the vega book before the first mark.
+fn reference_price(store: &Arc<Mutex<VegaStore>>) -> Option<f64> {
+    let md = store.lock().unwrap().get_market_data();
+    let mark = md.mark_price.parse::<f64>().unwrap_or_default();
+    if mark > 0.0 {
+        return Some(mark);
+    }
+    let bid = md.best_bid_price.parse::<f64>().unwrap_or_default();
+    let ask = md.best_offer_price.parse::<f64>().unwrap_or_default();
+    if bid > 0.0 && ask > 0.0 {
+        return Some((bid + ask) / 2.0);
+    }
+    return None;
+}
+
+// Cancels the given orders by ID, the ladder is left alone.
+async fn cancel_orders(w1: &mut Transact, c: &Config, orders: &Vec<Order>) {
+    if c.dryrun {
+        return;
+    }
+    let batch = BatchMarketInstructions {
+        cancellations: orders
+            .iter()
+            .map(|o| OrderCancellation {
+                order_id: o.id.clone(),
+                market_id: c.vega_market.clone(),
+            })
+            .collect(),
+        amendments: vec![],
+        submissions: vec![],
+        stop_orders_cancellation: vec![],
+        stop_orders_submission: vec![],
+        update_margin_mode: vec![],
+    };
+    match w1.send(Command::BatchMarketInstructions(batch)).await {
+        Ok(o) => info!("cancel flatten orders result: {:?}", o),
+        Err(e) => error!("cancel flatten orders error: {:?}", e),
+    };
+}
+
+// Unwinds our position one slice every flatten_interval until open_volume is
+// zero. Gives up after flatten_timeout, returns true if we ended up flat; in
+// dryrun the first slice is only logged and the position left as it is.
+// Before each slice the previous one (pegged slices rest on the book) is
+// cancelled and we wait up to flatten_interval for the orders stream to show
+// it gone; whatever is still live then is taken off the size of the next one.
+pub async fn flatten(w1: &mut Transact, c: &Config, store: &Arc<Mutex<VegaStore>>) -> bool {
+    let mkt = store.lock().unwrap().get_market();
+    let asset = match get_asset(&mkt) {
+        MarketAsset::Future(a) => store.lock().unwrap().get_asset(a),
+        MarketAsset::Perpetual(a) => store.lock().unwrap().get_asset(a),
+        MarketAsset::Spot(_base, quote) => store.lock().unwrap().get_asset(quote),
+    };
+    let d = Decimals::new(&mkt, &asset);
+    let slice = (c.flatten_slice * d.position_factor) as u64;
+    let tick = (c.tick_size * d.price_factor) as u64;
+    let interval = Duration::from_secs_f64(c.flatten_interval);
+    let party_id = w1.public_key();
+
+    let started = Instant::now();
+    let mut cancelled_at: Option<Instant> = None;
+    loop {
+        if started.elapsed() > Duration::from_secs_f64(c.flatten_timeout) {
+            error!("could not flatten our position within {}s, giving up", c.flatten_timeout);
+            return false;
+        }
+
+        let (position, positions_loaded, live, fills) = {
+            let s = store.lock().unwrap();
+            let live: Vec<Order> = s
+                .get_my_orders()
+                .into_iter()
+                .filter(|o| o.reference == FLATTEN_REFERENCE)
+                .collect();
+            (s.get_position(&party_id), s.positions_loaded(), live, s.get_fills())
+        };
+        if !positions_loaded {
+            info!("waiting for our position");
+            time::sleep(interval).await;
+            continue;
+        }
+        let (reported, updated_at) = position.map(|p| (p.open_volume, p.updated_at)).unwrap_or((0, 0));
+        let open_volume = unreported_open_volume(reported, updated_at, &fills);
+        if open_volume != reported {
+            info!(
+                "position of {} is {} after the fills the positions stream hasn't caught up with",
+                reported as f64 / d.position_factor,
+                open_volume as f64 / d.position_factor
+            );
+        }
+
+        if live.is_empty() {
+            cancelled_at = None;
+        } else {
+            match cancelled_at {
+                None => {
+                    info!("cancelling {} live flatten orders", live.len());
+                    cancel_orders(w1, c, &live).await;
+                    cancelled_at = Some(Instant::now());
+                    time::sleep(CANCEL_POLL).await;
+                    continue;
+                }
+                Some(t) if t.elapsed() < interval => {
+                    time::sleep(CANCEL_POLL).await;
+                    continue;
+                }
+                Some(_) => {
+                    warn!("{} flatten orders still live after cancelling them, sizing around them", live.len());
+                    cancelled_at = None;
+                }
+            }
+        }
+        if open_volume == 0 && live.is_empty() {
+            info!("position is flat");
+            return true;
+        }
+        let in_flight: u64 = live.iter().map(|o| o.remaining).sum();
+
+        let reference = match reference_price(store) {
+            Some(p) => p,
+            None => {
+                warn!("no reference price to flatten against yet");
+                time::sleep(interval).await;
+                continue;
+            }
+        };
+        if c.flatten_mode == FlattenMode::Pegged {
+            let md = store.lock().unwrap().get_market_data();
+            let mid = (md.best_bid_price.parse::<f64>().unwrap_or_default()
+                + md.best_offer_price.parse::<f64>().unwrap_or_default())
+                / 2.0;
+            if (mid - reference).abs() > reference * c.flatten_max_slippage_bp / 10_000.0 {
+                warn!("mid {} is too far from the reference {}, not pegging to it", mid, reference);
+                time::sleep(interval).await;
+                continue;
+            }
+        }
+
+        let expires_at = (SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards") + interval)
+            .as_nanos() as i64;
+        let order = match child_order(
+            &c.vega_market,
+            c.flatten_mode,
+            open_volume,
+            in_flight,
+            slice,
+            reference,
+            c.flatten_max_slippage_bp,
+            tick,
+            expires_at,
+        ) {
+            Some(o) => o,
+            None => {
+                info!(
+                    "live flatten orders of {} already cover our position of {}",
+                    in_flight as f64 / d.position_factor,
+                    open_volume as f64 / d.position_factor
+                );
+                time::sleep(interval).await;
+                continue;
+            }
+        };
+
+        info!(
+            "flattening position of {}: {:?} {} @ {} (reference {})",
+            open_volume as f64 / d.position_factor,
+            order.side,
+            order.size as f64 / d.position_factor,
+            order.price,
+            reference / d.price_factor
+        );
+        if c.dryrun {
+            info!("dryrun mode, simulated the flatten order {:?}, our position is unchanged", order);
+            return true;
+        }
+        match w1.send(Command::OrderSubmission(order)).await {
+            Ok(o) => info!("flatten order result: {:?}", o),
+            Err(e) => error!("flatten order error: {:?}", e),
+        };
+        time::sleep(interval).await;
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    fn fill(side: Side, size: u64, timestamp: u64) -> Fill {
+        return Fill {
+            trade_id: "t".to_string(),
+            order_id: "o".to_string(),
+            timestamp,
+            side,
+            price: 1000.0,
+            size,
+            fee: 0.0,
+            is_maker: false,
+            block_best_bid: 0.0,
+            block_best_ask: 0.0,
+            seq: 0,
+        };
+    }
+
+    #[test]
+    fn test_unreported_open_volume() {
+        // long 25 as of t=10, the sell at t=10 is in it already, the one at t=12 isn't
+        let fills = vec![fill(Side::Sell, 10, 10), fill(Side::Sell, 10, 12)];
+        assert_eq!(unreported_open_volume(25, 10, &fills), 15);
+        assert_eq!(unreported_open_volume(15, 12, &fills), 15);
+        // short, buying back
+        assert_eq!(unreported_open_volume(-5, 0, &vec![fill(Side::Buy, 5, 1)]), 0);
+        assert_eq!(unreported_open_volume(7, 0, &vec![]), 7);
+    }
+
+    #[test]
+    fn test_aggressive_child() {
+        assert!(child_order("m", FlattenMode::Aggressive, 0, 0, 10, 1000.0, 50.0, 1, 0).is_none());
+
+        // long 25, sell a slice of 10 no lower than 1000 - 50bp
+        let o = child_order("m", FlattenMode::Aggressive, 25, 0, 10, 1000.0, 50.0, 2, 0).unwrap();
+        assert_eq!(o.side, Side::Sell as i32);
+        assert_eq!(o.size, 10);
+        assert_eq!(o.price, "996");
+        assert!(o.reduce_only);
+        assert_eq!(o.time_in_force, TimeInForce::Ioc as i32);
+
+        // short 5, buy it all back no higher than 1005
+        let o = child_order("m", FlattenMode::Aggressive, -5, 0, 10, 1000.0, 50.0, 2, 0).unwrap();
+        assert_eq!(o.side, Side::Buy as i32);
+        assert_eq!(o.size, 5);
+        assert_eq!(o.price, "1004");
+
+        assert_eq!(child_order("m", FlattenMode::Aggressive, -5, 0, 0, 1000.0, 50.0, 1, 0).unwrap().size, 5);
+    }
+
+    #[test]
+    fn test_pegged_child() {
+        let o = child_order("m", FlattenMode::Pegged, 25, 0, 10, 1000.0, 50.0, 2, 77).unwrap();
+        assert_eq!(o.size, 10);
+        assert_eq!(o.expires_at, 77);
+        assert_eq!(o.pegged_order.unwrap().offset, "2");
+        assert_eq!(o.reference, FLATTEN_REFERENCE);
+        assert!(!o.reduce_only);
+    }
+
+    #[test]
+    fn test_child_sized_around_live_orders() {
+        // long 25 with 20 still resting from the previous slice
+        let o = child_order("m", FlattenMode::Pegged, 25, 20, 10, 1000.0, 50.0, 2, 77).unwrap();
+        assert_eq!(o.size, 5);
+        let o = child_order("m", FlattenMode::Pegged, -25, 20, 0, 1000.0, 50.0, 2, 77).unwrap();
+        assert_eq!(o.side, Side::Buy as i32);
+        assert_eq!(o.size, 5);
+        // covered already, a new slice could only flip the position
+        assert!(child_order("m", FlattenMode::Pegged, 25, 25, 10, 1000.0, 50.0, 2, 77).is_none());
+        assert!(child_order("m", FlattenMode::Pegged, 25, 30, 10, 1000.0, 50.0, 2, 77).is_none());
+    }
+}
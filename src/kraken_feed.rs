@@ -0,0 +1,267 @@
+use chrono::DateTime;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tungstenite::{connect, Message};
+
+use crate::backoff::Backoff;
+use crate::ref_price::{Book, BookSide, RefPrice};
+use crate::ws_util;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KrakenChannel {
+    // best bid / ask with sizes
+    Ticker,
+    // order book with the configured depth
+    Book,
+}
+
+impl Default for KrakenChannel {
+    fn default() -> Self {
+        KrakenChannel::Ticker
+    }
+}
+
+impl KrakenChannel {
+    fn as_str(&self) -> &'static str {
+        match self {
+            KrakenChannel::Ticker => "ticker",
+            KrakenChannel::Book => "book",
+        }
+    }
+}
+
+// book depths the v2 api accepts
+pub const KRAKEN_BOOK_DEPTHS: [u32; 5] = [10, 25, 100, 500, 1000];
+
+// v2 sends prices and sizes as json numbers
+#[derive(Deserialize, Debug)]
+struct WsMessage {
+    channel: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    data: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TickerData {
+    symbol: String,
+    bid: f64,
+    bid_qty: f64,
+    ask: f64,
+    ask_qty: f64,
+    timestamp: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BookLevel {
+    price: f64,
+    qty: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct BookData {
+    symbol: String,
+    bids: Vec<BookLevel>,
+    asks: Vec<BookLevel>,
+    timestamp: Option<String>,
+}
+
+// Kraken v2 public websocket feed, reconnects with backoff forever.
+pub fn start(ws_url: String, symbol: String, channel: KrakenChannel, depth: u32, rp: Arc<Mutex<RefPrice>>) {
+    ws_util::reconnect_forever("kraken", |backoff| run(&ws_url, &symbol, channel, depth, rp.clone(), backoff));
+}
+
+// kraken sends a heartbeat every second while subscribed, so a quiet
+// connection is a dead one
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn run(
+    url: &str,
+    symbol: &str,
+    channel: KrakenChannel,
+    depth: u32,
+    rp: Arc<Mutex<RefPrice>>,
+    backoff: &mut Backoff,
+) -> Result<(), tungstenite::Error> {
+    info!("opening websocket with kraken API at: {}", url);
+    let (mut socket, _) = connect(url)?;
+    info!("connected to kraken successfully");
+    ws_util::set_read_timeout(&mut socket, Some(READ_TIMEOUT))?;
+
+    let request = subscribe_request(symbol, channel, depth);
+    socket.write_message(Message::Text(request.to_string()))?;
+
+    let mut book = Book::new();
+    loop {
+        let msg = socket.read_message()?;
+        let text = match msg {
+            Message::Text(t) => t,
+            Message::Close(frame) => {
+                info!("kraken sent close frame: {:?}", frame);
+                return Ok(());
+            }
+            _ => continue,
+        };
+
+        if apply_message(&text, symbol, depth as usize, &mut book, &rp) {
+            backoff.reset();
+        }
+    }
+}
+
+// The ticker defaults to updating on trades, ask for every change to the
+// top of book instead.
+fn subscribe_request(symbol: &str, channel: KrakenChannel, depth: u32) -> serde_json::Value {
+    let mut params = serde_json::json!({
+        "channel": channel.as_str(),
+        "symbol": [symbol],
+    });
+    match channel {
+        KrakenChannel::Ticker => params["event_trigger"] = serde_json::json!("bbo"),
+        KrakenChannel::Book => params["depth"] = serde_json::json!(depth),
+    }
+    return serde_json::json!({"method": "subscribe", "params": params});
+}
+
+fn timestamp_millis(ts: &Option<String>) -> u64 {
+    return ts
+        .as_ref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.timestamp_millis() as u64)
+        .unwrap_or(0);
+}
+
+// Returns true if the message updated the reference price. Heartbeats,
+// status messages, subscribe acks and anything malformed are skipped.
+fn apply_message(text: &str, symbol: &str, depth: usize, book: &mut Book, rp: &Arc<Mutex<RefPrice>>) -> bool {
+    let msg = match serde_json::from_str::<WsMessage>(text) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("skipping malformed kraken message: {} ({})", text, e);
+            return false;
+        }
+    };
+    let data = match msg.data {
+        Some(d) => d,
+        None => return false,
+    };
+
+    match msg.channel.as_deref() {
+        Some("ticker") => {
+            let mut updated = false;
+            for d in data.into_iter() {
+                let t = match serde_json::from_value::<TickerData>(d) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        warn!("skipping bad kraken ticker: {}", e);
+                        continue;
+                    }
+                };
+                if t.symbol != symbol || t.bid <= 0.0 || t.ask <= 0.0 {
+                    continue;
+                }
+                rp.lock().unwrap().set_top(t.bid, t.bid_qty, t.ask, t.ask_qty, timestamp_millis(&t.timestamp));
+                updated = true;
+            }
+            return updated;
+        }
+        Some("book") => {
+            let mut ts = 0;
+            for d in data.into_iter() {
+                let b = match serde_json::from_value::<BookData>(d) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        warn!("skipping bad kraken book: {}", e);
+                        continue;
+                    }
+                };
+                if b.symbol != symbol {
+                    continue;
+                }
+                if msg.kind.as_deref() == Some("snapshot") {
+                    book.clear();
+                }
+                for l in b.bids.iter() {
+                    book.set_level(BookSide::Bid, l.price, l.qty);
+                }
+                for l in b.asks.iter() {
+                    book.set_level(BookSide::Ask, l.price, l.qty);
+                }
+                ts = timestamp_millis(&b.timestamp);
+            }
+            // kraken doesn't send deletes for levels pushed out of the
+            // subscribed depth, we have to drop them ourselves
+            book.truncate(depth);
+            return rp.lock().unwrap().set_book(book.clone(), ts);
+        }
+        _ => return false,
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribe_request() {
+        assert_eq!(
+            subscribe_request("BTC/USD", KrakenChannel::Ticker, 10),
+            serde_json::json!({
+                "method": "subscribe",
+                "params": {"channel": "ticker", "symbol": ["BTC/USD"], "event_trigger": "bbo"},
+            })
+        );
+        assert_eq!(
+            subscribe_request("BTC/USD", KrakenChannel::Book, 25),
+            serde_json::json!({
+                "method": "subscribe",
+                "params": {"channel": "book", "symbol": ["BTC/USD"], "depth": 25},
+            })
+        );
+    }
+
+    #[test]
+    fn test_apply_ticker() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut book = Book::new();
+        let ticker = r#"{"channel":"ticker","type":"snapshot","data":[{"symbol":"BTC/USD","bid":59000.1,"bid_qty":0.50000000,"ask":59000.2,"ask_qty":1.25000000,"last":59000.1,"volume":1234.5,"vwap":58800.0,"low":58000.0,"high":60000.0,"change":300.0,"change_pct":0.51}]}"#;
+        assert!(apply_message(ticker, "BTC/USD", 10, &mut book, &rp));
+        assert_eq!(rp.lock().unwrap().get(), (59000.1, 59000.2));
+        assert_eq!(rp.lock().unwrap().sizes(), (0.5, 1.25));
+        assert!(!apply_message(ticker, "ETH/USD", 10, &mut book, &rp));
+    }
+
+    #[test]
+    fn test_apply_book_snapshot_and_update() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut book = Book::new();
+        let snapshot = r#"{"channel":"book","type":"snapshot","data":[{"symbol":"BTC/USD","bids":[{"price":59000.1,"qty":0.5},{"price":59000.0,"qty":1.0}],"asks":[{"price":59000.2,"qty":0.3},{"price":59000.5,"qty":2.0}],"checksum":2439117997}]}"#;
+        assert!(apply_message(snapshot, "BTC/USD", 2, &mut book, &rp));
+        assert_eq!(rp.lock().unwrap().get(), (59000.1, 59000.2));
+
+        // new best ask pushes 59000.5 out of the 2 level book
+        let update = r#"{"channel":"book","type":"update","data":[{"symbol":"BTC/USD","bids":[{"price":59000.1,"qty":0}],"asks":[{"price":59000.15,"qty":0.1}],"checksum":1234567,"timestamp":"2024-05-02T10:00:00.123456Z"}]}"#;
+        assert!(apply_message(update, "BTC/USD", 2, &mut book, &rp));
+        let rp = rp.lock().unwrap();
+        assert_eq!(rp.get(), (59000.0, 59000.15));
+        assert_eq!(rp.book().asks(), &vec![(59000.15, 0.1), (59000.2, 0.3)]);
+        assert_eq!(rp.exchange_ts(), 1714644000123);
+    }
+
+    #[test]
+    fn test_apply_skips_junk() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let mut book = Book::new();
+        let heartbeat = r#"{"channel":"heartbeat"}"#;
+        let status = r#"{"channel":"status","data":[{"api_version":"v2","connection_id":12393906104898154338,"system":"online","version":"2.0.0"}],"type":"update"}"#;
+        let ack = r#"{"method":"subscribe","result":{"channel":"ticker","snapshot":true,"symbol":"BTC/USD"},"success":true,"time_in":"2024-05-02T10:00:00.000000Z","time_out":"2024-05-02T10:00:00.000100Z"}"#;
+        assert!(!apply_message(heartbeat, "BTC/USD", 10, &mut book, &rp));
+        assert!(!apply_message(status, "BTC/USD", 10, &mut book, &rp));
+        assert!(!apply_message(ack, "BTC/USD", 10, &mut book, &rp));
+        assert!(!apply_message("not json", "BTC/USD", 10, &mut book, &rp));
+        assert_eq!(rp.lock().unwrap().get(), (0.0, 0.0));
+    }
+}
@@ -1,5 +1,5 @@
 use futures_util::lock::MutexGuard as FuturesUtilsMutexGuard;
-use log::info;
+use log::{error, info, warn};
 use num_traits::ToPrimitive;
 use vega_protobufs::vega::events::v1::ExpiredOrders;
 use core::num;
@@ -29,13 +29,31 @@ use vega_protobufs::vega::{
 use vega_protobufs::vega::{Asset, Position};
 use vega_protobufs::vega::MarketData;
 
-use crate::{vega_store2::VegaStore};
+use crate::{vega_store2::{Balances, VegaStore}};
 use crate::{Config, vega_store2};
 use crate::opt_offsets;
 use crate::estimate_params::{self, estimate_lambda2, estimate_kappa};
 use crate::strategy2::{Decimals, get_asset, MarketAsset};
 
 
+// The one-off liquidity commands run right after startup, give the accounts
+// snapshot a few seconds to arrive.
+async fn wait_for_balances(store: &Arc<Mutex<VegaStore>>, asset_id: &str) -> Option<Balances> {
+    for _ in 0..50 {
+        if let Some(b) = store.lock().unwrap().get_balances(asset_id) {
+            return Some(b);
+        }
+        time::sleep(Duration::from_millis(200)).await;
+    }
+    return None;
+}
+
+// false if our bond account holds less than the configured commitment,
+// e.g. because it was slashed for missing the SLA
+pub fn bond_is_funded(balances: &Balances, bond_amount: u64, d: &Decimals) -> bool {
+    return balances.bond > 0.0 && balances.bond >= (bond_amount as f64 * d.asset_factor).floor();
+}
+
 pub async fn create_liquidity_commitment(
     mut w1: Transact,
     config: Config,
@@ -50,6 +68,20 @@ pub async fn create_liquidity_commitment(
     };
     let d = Decimals::new(&mkt, &asset);
 
+    let bond = config.bond_amount as f64 * d.asset_factor;
+    match wait_for_balances(&store, &asset.id).await {
+        Some(b) if b.general < bond => {
+            error!(
+                "general balance {} is less than the bond amount {}, not submitting liquidity",
+                b.general / d.asset_factor,
+                config.bond_amount
+            );
+            return;
+        }
+        Some(_) => {}
+        None => warn!("no account balances yet, submitting liquidity without checking the general balance"),
+    }
+
     if !config.dryrun {
         match w1
             .send(Command::LiquidityProvisionSubmission(get_liquidity_submission_transaction(
@@ -114,6 +146,24 @@ pub async fn update_liquidity_commitment(
     };
     let d = Decimals::new(&mkt, &asset);
 
+    match wait_for_balances(&store, &asset.id).await {
+        Some(b) if b.bond <= 0.0 => {
+            error!("no bond on market {}, submit liquidity instead of amending it", config.vega_market);
+            return;
+        }
+        Some(b) if config.bond_amount as f64 * d.asset_factor - b.bond > b.general => {
+            error!(
+                "general balance {} can't cover raising the bond from {} to {}, not amending liquidity",
+                b.general / d.asset_factor,
+                b.bond / d.asset_factor,
+                config.bond_amount
+            );
+            return;
+        }
+        Some(_) => {}
+        None => warn!("no account balances yet, amending liquidity without checking the bond"),
+    }
+
     if !config.dryrun {
         match w1
             .send(Command::LiquidityProvisionAmendment(get_liquidity_amendment_transaction(
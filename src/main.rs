@@ -1,23 +1,48 @@
 use clap::{Arg, Parser};
-use log::info;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
-use tokio::time;
 use vega_crypto::Credentials;
 use vega_crypto::Transact;
 use vega_protobufs::datanode::api::v2::trading_data_service_client::TradingDataServiceClient;
-use vega_store2::update_forever;
+use vega_store2::{update_forever, VegaPriceKind, VegaPriceSource};
+use price_source::{Aggregation, FeedPriceSource, FxRate, PriceSource, RefPriceMode, Venue, WeightedSource};
+use bybit_feed::{BybitCategory, BybitMode};
+use coinbase_feed::CoinbaseChannel;
+use okx_feed::OkxChannel;
+use kraken_feed::{KrakenChannel, KRAKEN_BOOK_DEPTHS};
+use binance_ws::BinanceStream;
+use fair_value::FairValueMode;
+use shutdown::ShutdownLiquidity;
+use flatten::FlattenMode;
 
 // mod api;
+mod backoff;
 mod ref_price;
+mod price_source;
+mod fair_value;
+mod metrics;
 mod binance_ws;
 mod bybit_feed;
+mod coinbase_feed;
+mod okx_feed;
+mod kraken_feed;
+mod ws_util;
 mod strategy2;
 mod liquidity_vega;
 mod vega_store2;
+mod vega_book;
+mod node_pool;
+mod order_diff;
+mod fills;
+mod pnl;
+mod risk;
+mod shutdown;
+mod flatten;
 mod opt_offsets;
 mod estimate_params;
 
@@ -36,24 +61,115 @@ struct Cli {
     
     #[arg(long, default_value_t = false)]
     amend_liquidity: bool,
+
+    /// Unwind our position and exit
+    #[arg(long, default_value_t = false)]
+    flatten: bool,
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RefSourceConfig {
+    venue: Venue,
+    #[serde(default = "default_one")]
+    weight: f64,
+    #[serde(default = "default_one")]
+    price_scaling: f64,
+    // vega_mark / vega_external_twap / vega_indicative are quoted at price +/- this many bp
+    #[serde(default)]
+    half_spread_bp: f64,
 }
 
+// Optional quote currency conversion feed, applied to all external sources.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FxConfig {
+    // binance, bybit or coinbase, using the same urls as the reference feeds
+    venue: Venue,
+    market: String,
+    // the market quotes the rate the other way round, e.g. USDCUSDT
+    #[serde(default)]
+    invert: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct OkxConfig {
+    #[serde(default = "default_okx_ws_url")]
+    ws_url: String,
+    // instrument id, e.g. VEGA-USDT or BTC-USDT-SWAP
+    symbol: String,
+    // tickers or books5
+    #[serde(default)]
+    channel: OkxChannel,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct KrakenConfig {
+    #[serde(default = "default_kraken_ws_url")]
+    ws_url: String,
+    // v2 symbol, e.g. BTC/USD
+    symbol: String,
+    // ticker or book
+    #[serde(default)]
+    channel: KrakenChannel,
+    // book channel only, one of 10, 25, 100, 500 or 1000
+    #[serde(default = "default_kraken_depth")]
+    depth: u32,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Config {
     port: u16,
+    #[serde(default)]
     vega_grpc_url: String,
+    // datanodes to fail over between, vega_grpc_url is used if this is empty
+    #[serde(default)]
+    vega_grpc_urls: Vec<String>,
+    // switch datanode when the current one is this many blocks behind the best
+    #[serde(default = "default_max_node_block_lag")]
+    max_node_block_lag: u64,
+    // seconds between datanode health checks
+    #[serde(default = "default_node_check_interval")]
+    node_check_interval: f64,
     binance_ws_url: String,
     bybit_url: String,
+    #[serde(default = "default_bybit_ws_url")]
+    bybit_ws_url: String,
     bybit_market: String,
+    #[serde(default)]
+    bybit_category: BybitCategory,
+    #[serde(default)]
+    bybit_mode: BybitMode,
+    // orderbook depth to subscribe to, e.g. 1 or 50
+    #[serde(default = "default_bybit_depth")]
+    bybit_depth: u32,
+    #[serde(default = "default_coinbase_ws_url")]
+    coinbase_ws_url: String,
+    // product id, e.g. BTC-USD
+    #[serde(default)]
+    coinbase_market: String,
+    // ticker or level2
+    #[serde(default)]
+    coinbase_channel: CoinbaseChannel,
+    // needed when okx / kraken are used as ref sources
+    okx: Option<OkxConfig>,
+    kraken: Option<KrakenConfig>,
     wallet_mnemonic_1: String,
     vega_market: String,
     binance_market: String,
+    // ticker, book_ticker or depth5/depth10/depth20
+    #[serde(default)]
+    binance_stream: BinanceStream,
+    // depth streams only, e.g. 100 for btcusdt@depth5@100ms
+    binance_depth_update_ms: Option<u64>,
     binance_price_scaling: f64,
     ref_price_scaling: f64,
     bond_amount: u64,
     lp_fee_bid: f64,
     volume_of_notional: u64,
+    // expected initial margin per unit of notional, used to scale volume_of_notional
+    // down to what our balance can cover; 0 disables
+    #[serde(default)]
+    margin_per_notional: f64,
     buy_to_sell_ratio: f64,
     levels: u64,
     step: f64,
@@ -64,22 +180,241 @@ struct Config {
     pos_lim_scaling: f64,
     kappa: f64,
     kappa_weight: f64,
+    // estimate kappa from how far from the mid our own orders got filled,
+    // from all the trades on the market until we have min_kappa_fills maker
+    // fills in the estimation window
+    #[serde(default = "default_true")]
+    kappa_from_fills: bool,
+    #[serde(default = "default_min_kappa_fills")]
+    min_kappa_fills: u64,
     lambd: f64,
     phi: f64,
     use_mid: bool,
     use_vega_bidask: bool,
     use_binance_bidask: bool,
     use_bybit_bidask: bool,
+    #[serde(default)]
+    use_coinbase_bidask: bool,
     use_vega_trades: bool,
+    // reference price sources, if empty we build them from the use_*_bidask flags
+    #[serde(default)]
+    ref_sources: Vec<RefSourceConfig>,
+    #[serde(default)]
+    ref_aggregation: Aggregation,
+    // drop a venue whose mid is further than this from the median mid of the others, 0 disables
+    #[serde(default = "default_max_source_deviation_bp")]
+    max_source_deviation_bp: f64,
+    // live fx rate that replaces the static price_scaling of the external sources while fresh
+    fx: Option<FxConfig>,
+    // top of book or vwap to fill ref_vwap_notional on each side of the external books
+    #[serde(default)]
+    ref_price_mode: RefPriceMode,
+    // quote currency notional for the vwap mode, 0 means use volume_of_notional
+    #[serde(default)]
+    ref_vwap_notional: f64,
+    // mid, microprice or imbalance; the last two move our quotes towards the heavier side
+    #[serde(default)]
+    fair_value_mode: FairValueMode,
+    // levels per side used by the imbalance fair value mode
+    #[serde(default = "default_imbalance_depth")]
+    imbalance_depth: usize,
+    // 1.0 is the full microprice adjustment, 0.0 is the plain mid
+    #[serde(default = "default_one")]
+    imbalance_weight: f64,
+    // weight of vega's own top of book size imbalance against the reference sources'
+    #[serde(default = "default_one")]
+    vega_imbalance_weight: f64,
     allow_negative_offset: bool,
+    // only quote behind at least this much volume (in contracts) of other
+    // orders on the vega book, 0 disables
+    #[serde(default)]
+    min_volume_ahead: f64,
+    // amend / cancel / submit only what changed against our live orders instead
+    // of cancelling everything and resubmitting the whole ladder
+    #[serde(default)]
+    incremental_orders: bool,
     gtt_length: u64,
     dispose_prob: f64,
     dispose_q_lower: i64,
     dispose_q_upper: i64,
     submission_rate: f64,
+    // seconds after which an external reference price is considered stale
+    #[serde(default = "default_max_ref_price_age")]
+    max_ref_price_age: f64,
+    // seconds without vega market data after which we stop quoting
+    #[serde(default = "default_max_vega_stream_age")]
+    max_vega_stream_age: f64,
+    // warn when our P&L and the positions stream differ by more than this,
+    // in asset units
+    #[serde(default = "default_max_pnl_drift")]
+    max_pnl_drift: f64,
+    // risk limits in asset units, 0 disables. Tripping one cancels all our
+    // orders and halts quoting until the halt file is deleted (or a restart
+    // if there is no halt file)
+    #[serde(default)]
+    max_session_loss: f64,
+    // from the session's peak P&L
+    #[serde(default)]
+    max_drawdown: f64,
+    #[serde(default)]
+    max_position_notional: f64,
+    #[serde(default)]
+    max_order_notional: f64,
+    // unwind the position with the flatten settings below when a limit trips
+    #[serde(default)]
+    risk_flatten_on_trip: bool,
+    #[serde(default)]
+    risk_halt_file: Option<String>,
+    // on SIGINT / SIGTERM we always cancel our orders, then optionally amend
+    // or cancel the liquidity commitment and flatten the position
+    #[serde(default)]
+    shutdown_liquidity: ShutdownLiquidity,
+    // bond to amend to with shutdown_liquidity amend
+    #[serde(default)]
+    shutdown_bond_amount: u64,
+    #[serde(default)]
+    shutdown_flatten: bool,
+    // seconds each shutdown step may take, except the flatten which gets
+    // flatten_timeout; with shutdown_flatten that is what dominates the time
+    // we need, and all together must fit in the stop timeout of whatever
+    // supervises us
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: f64,
+    // flattening (--flatten, risk_flatten_on_trip, shutdown_flatten) sends one
+    // child order of flatten_slice contracts (0 for the whole position) every
+    // flatten_interval seconds, at most flatten_max_slippage_bp from the mark
+    // price, and gives up after flatten_timeout seconds
+    #[serde(default)]
+    flatten_mode: FlattenMode,
+    #[serde(default)]
+    flatten_slice: f64,
+    #[serde(default = "default_flatten_max_slippage_bp")]
+    flatten_max_slippage_bp: f64,
+    #[serde(default = "default_flatten_interval")]
+    flatten_interval: f64,
+    #[serde(default = "default_flatten_timeout")]
+    flatten_timeout: f64,
     dryrun: bool,
 }
 
+fn default_max_ref_price_age() -> f64 {
+    10.0
+}
+
+fn default_flatten_max_slippage_bp() -> f64 {
+    50.0
+}
+
+fn default_flatten_interval() -> f64 {
+    2.0
+}
+
+fn default_flatten_timeout() -> f64 {
+    300.0
+}
+
+fn default_shutdown_timeout() -> f64 {
+    10.0
+}
+
+fn default_max_pnl_drift() -> f64 {
+    1.0
+}
+
+fn default_max_node_block_lag() -> u64 {
+    10
+}
+
+fn default_node_check_interval() -> f64 {
+    10.0
+}
+
+fn default_max_vega_stream_age() -> f64 {
+    10.0
+}
+
+fn default_bybit_ws_url() -> String {
+    "wss://stream.bybit.com/v5/public".to_string()
+}
+
+fn default_coinbase_ws_url() -> String {
+    "wss://advanced-trade-ws.coinbase.com".to_string()
+}
+
+fn default_okx_ws_url() -> String {
+    "wss://ws.okx.com:8443/ws/v5/public".to_string()
+}
+
+fn default_kraken_ws_url() -> String {
+    "wss://ws.kraken.com/v2".to_string()
+}
+
+fn default_kraken_depth() -> u32 {
+    10
+}
+
+fn default_bybit_depth() -> u32 {
+    1
+}
+
+fn default_imbalance_depth() -> usize {
+    5
+}
+
+fn default_max_source_deviation_bp() -> f64 {
+    100.0
+}
+
+fn default_one() -> f64 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_min_kappa_fills() -> u64 {
+    10
+}
+
+impl Config {
+    fn get_grpc_urls(&self) -> Vec<String> {
+        if !self.vega_grpc_urls.is_empty() {
+            return self.vega_grpc_urls.clone();
+        }
+        if self.vega_grpc_url.is_empty() {
+            return vec![];
+        }
+        return vec![self.vega_grpc_url.clone()];
+    }
+
+    fn get_ref_sources(&self) -> Vec<RefSourceConfig> {
+        if !self.ref_sources.is_empty() {
+            return self.ref_sources.clone();
+        }
+
+        let mut sources = vec![];
+        if self.use_vega_bidask {
+            sources.push(RefSourceConfig { venue: Venue::Vega, weight: 1.0, price_scaling: 1.0, half_spread_bp: 0.0 });
+        }
+        if self.use_binance_bidask {
+            sources.push(RefSourceConfig {
+                venue: Venue::Binance,
+                weight: 1.0,
+                price_scaling: self.binance_price_scaling,
+                half_spread_bp: 0.0,
+            });
+        }
+        if self.use_bybit_bidask {
+            sources.push(RefSourceConfig { venue: Venue::Bybit, weight: 1.0, price_scaling: 1.0, half_spread_bp: 0.0 });
+        }
+        if self.use_coinbase_bidask {
+            sources.push(RefSourceConfig { venue: Venue::Coinbase, weight: 1.0, price_scaling: 1.0, half_spread_bp: 0.0 });
+        }
+        return sources;
+    }
+}
+
 fn config_validation(c: Config) {
    if c.buy_to_sell_ratio < 1e-8 {
         panic!("buy to sell ratio must be >= 1e-8"); 
@@ -143,8 +478,119 @@ fn config_validation(c: Config) {
         panic!("config file submission_rate must be >= 0.01, otherwise you risk getting spam-banned as we don't increase PoW difficulty properly.");
     }
 
-    if !c.use_binance_bidask && !c.use_vega_bidask && ! c.use_bybit_bidask {
-        panic!("at the moment we need to use at least one of binance, bybit, vega bid/asks to set prices");
+    if let Some(fx) = &c.fx {
+        match fx.venue {
+            Venue::Binance | Venue::Bybit | Venue::Coinbase => {}
+            _ => panic!("fx venue must be binance, bybit or coinbase"),
+        }
+    }
+
+    if c.max_source_deviation_bp < 0.0 {
+        panic!("config file max_source_deviation_bp must be >= 0.0");
+    }
+
+    if c.ref_vwap_notional < 0.0 {
+        panic!("config file ref_vwap_notional must be >= 0.0");
+    }
+
+    if c.imbalance_weight < 0.0 || c.imbalance_weight > 1.0 {
+        panic!("config file imbalance_weight must be >= 0.0 and <= 1.0");
+    }
+
+    if c.vega_imbalance_weight < 0.0 {
+        panic!("config file vega_imbalance_weight must be >= 0.0");
+    }
+
+    if c.imbalance_depth == 0 {
+        panic!("config file imbalance_depth must be > 0");
+    }
+
+    if let Some(kraken) = &c.kraken {
+        if !KRAKEN_BOOK_DEPTHS.contains(&kraken.depth) {
+            panic!("config file kraken depth must be one of {:?}", KRAKEN_BOOK_DEPTHS);
+        }
+    }
+
+    if c.max_ref_price_age <= 0.0 {
+        panic!("config file max_ref_price_age must be > 0.0");
+    }
+
+    if c.get_grpc_urls().is_empty() {
+        panic!("config file needs vega_grpc_url or vega_grpc_urls");
+    }
+
+    if c.node_check_interval <= 0.0 {
+        panic!("config file node_check_interval must be > 0.0");
+    }
+
+    if c.max_pnl_drift < 0.0 {
+        panic!("config file max_pnl_drift must be >= 0.0");
+    }
+
+    if c.max_session_loss < 0.0 || c.max_drawdown < 0.0 || c.max_position_notional < 0.0 || c.max_order_notional < 0.0 {
+        panic!("config file risk limits must be >= 0.0");
+    }
+
+    if c.flatten_slice < 0.0 {
+        panic!("config file flatten_slice must be >= 0.0");
+    }
+
+    if c.flatten_max_slippage_bp <= 0.0 {
+        panic!("config file flatten_max_slippage_bp must be > 0.0");
+    }
+
+    if c.flatten_interval <= 0.0 || c.flatten_timeout <= 0.0 {
+        panic!("config file flatten_interval and flatten_timeout must be > 0.0");
+    }
+
+    if c.shutdown_timeout <= 0.0 {
+        panic!("config file shutdown_timeout must be > 0.0");
+    }
+
+    if c.shutdown_liquidity == ShutdownLiquidity::Amend && c.shutdown_bond_amount == 0 {
+        panic!("config file shutdown_bond_amount must be > 0 to amend liquidity on shutdown, cancel it instead");
+    }
+
+    if c.min_volume_ahead < 0.0 {
+        panic!("config file min_volume_ahead must be >= 0.0");
+    }
+
+    if c.margin_per_notional < 0.0 {
+        panic!("config file margin_per_notional must be >= 0.0");
+    }
+
+    if c.max_vega_stream_age <= 0.0 {
+        panic!("config file max_vega_stream_age must be > 0.0");
+    }
+
+    let ref_sources = c.get_ref_sources();
+    if ref_sources.is_empty() {
+        panic!("we need at least one reference price source, set ref_sources or one of the use_*_bidask flags");
+    }
+
+    for s in ref_sources.iter() {
+        if s.weight < 0.0 {
+            panic!("ref source {:?} weight must be >= 0.0", s.venue);
+        }
+        if s.price_scaling <= 0.0 {
+            panic!("ref source {:?} price_scaling must be > 0.0", s.venue);
+        }
+        if s.venue.needs_half_spread() && s.half_spread_bp <= 0.0 {
+            panic!("ref source {:?} needs half_spread_bp > 0.0", s.venue);
+        }
+        if s.venue == Venue::Coinbase && c.coinbase_market.is_empty() {
+            panic!("config file coinbase_market must be set to use coinbase as a ref source");
+        }
+        if s.venue == Venue::Okx && c.okx.is_none() {
+            panic!("config file needs an okx section to use okx as a ref source");
+        }
+        if s.venue == Venue::Kraken && c.kraken.is_none() {
+            panic!("config file needs a kraken section to use kraken as a ref source");
+        }
+    }
+
+    if c.ref_aggregation == Aggregation::WeightedMid && ref_sources.iter().all(|s| s.weight <= 0.0) {
+        panic!("weighted_mid aggregation needs at least one ref source with weight > 0.0");
     }
 
     if c.dispose_prob < 0.0 || c.dispose_prob > 1.0 {
@@ -170,24 +616,51 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     config_validation(config.clone());
 
+    // start on the datanode furthest ahead
+    let grpc_urls = config.get_grpc_urls();
+    let node_health = node_pool::probe_all(
+        &grpc_urls,
+        &config.vega_market,
+        Duration::from_secs_f64(config.node_check_interval),
+    )
+    .await;
+    let best_node = match node_pool::pick_best(&node_health, None) {
+        Some(idx) => idx,
+        None => {
+            error!("none of the datanodes answered: {:?}", grpc_urls);
+            exit(1);
+        }
+    };
+    let pool = Arc::new(Mutex::new(node_pool::NodePool::new(grpc_urls, best_node)));
+    let addr = pool.lock().unwrap().current_url();
+    info!("using datanode {}", addr);
+
     let w1 = Transact::new(
         Credentials::Mnemonic(&config.wallet_mnemonic_1, 1),
-        config.vega_grpc_url.clone(),
+        addr.clone(),
     )
     .await?;
     info!("loaded wallet 1 with address {}", w1.public_key());
     
-    let addr = config.vega_grpc_url.clone();
     let mut tdclt = TradingDataServiceClient::connect(addr).await?;
     let vstore = Arc::new(Mutex::new(
-        vega_store2::VegaStore::new(&mut tdclt, &*config.vega_market).await?,
+        vega_store2::VegaStore::new(&mut tdclt, &*config.vega_market, &*w1.public_key()).await?,
     ));
+    vega_store2::warm_start(vstore.clone(), &mut tdclt, &config.vega_market, strategy2::ESTIMATION_WINDOW).await;
     update_forever(
         vstore.clone(),
-        tdclt,
+        pool.clone(),
         &*config.vega_market,
         &*w1.public_key().clone(),
     );
+    tokio::spawn(node_pool::monitor(
+        pool.clone(),
+        vstore.clone(),
+        config.vega_market.clone(),
+        config.max_node_block_lag,
+        Duration::from_secs_f64(config.max_vega_stream_age),
+        Duration::from_secs_f64(config.node_check_interval),
+    ));
 
 
     if cli.amend_liquidity {
@@ -226,6 +699,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         ).await;
         exit(0);
     }
+    else if cli.flatten {
+        let mut w = w1.clone();
+        if flatten::flatten(&mut w, &config, &vstore).await {
+            exit(0);
+        }
+        exit(1);
+    }
     else {
         // keep previous reference price around to avoid sending too many transactions
         let old_rp = Arc::new(Mutex::new(ref_price::RefPrice::new()));
@@ -233,48 +713,164 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let skip_counter_u = (config.gtt_length as f64 / config.submission_rate) as u64;
         let skip_counter = Arc::new(Mutex::new(skip_counter_u));
 
+        let ref_sources = config.get_ref_sources();
+        let uses_venue = |v: Venue| ref_sources.iter().any(|s| s.venue == v);
+
+        // the websocket feeds block on their sockets, each gets its own thread
+        // so they never hold up a tokio worker
+
         // mutex store for binance data
         let binance_rp = Arc::new(Mutex::new(ref_price::RefPrice::new()));
-        if config.use_binance_bidask {
-            tokio::spawn(binance_ws::start(
+        if uses_venue(Venue::Binance) {
+            let (url, mkt, stream, update_ms, rp) = (
                 config.binance_ws_url.clone(),
                 config.binance_market.clone(),
+                config.binance_stream,
+                config.binance_depth_update_ms,
                 binance_rp.clone(),
-            ));    
+            );
+            thread::spawn(move || {
+                if let Err(e) = binance_ws::start(url, mkt, stream, update_ms, rp) {
+                    error!("binance feed stopped: {}", e);
+                }
+            });
         }
 
         // mutex store for bybit data
         let bybit_rp = Arc::new(Mutex::new(ref_price::RefPrice::new()));
-        if config.use_bybit_bidask {
-            tokio::spawn(bybit_feed::start(
-                config.bybit_url.clone(),
-                config.bybit_market.clone(),
-                bybit_rp.clone(),
-                1000,
-            ));    
+        if uses_venue(Venue::Bybit) {
+            match config.bybit_mode {
+                BybitMode::Ws => {
+                    let (url, category, mkt, depth, rp) = (
+                        config.bybit_ws_url.clone(),
+                        config.bybit_category,
+                        config.bybit_market.clone(),
+                        config.bybit_depth,
+                        bybit_rp.clone(),
+                    );
+                    thread::spawn(move || bybit_feed::start_ws(url, category, mkt, depth, rp));
+                }
+                BybitMode::Rest => {
+                    tokio::spawn(bybit_feed::start(
+                        config.bybit_url.clone(),
+                        config.bybit_category,
+                        config.bybit_market.clone(),
+                        config.bybit_depth,
+                        bybit_rp.clone(),
+                        1000,
+                    ));
+                }
+            };
+        }
+
+        // mutex store for coinbase data
+        let coinbase_rp = Arc::new(Mutex::new(ref_price::RefPrice::new()));
+        if uses_venue(Venue::Coinbase) {
+            let (url, mkt, channel, rp) = (
+                config.coinbase_ws_url.clone(),
+                config.coinbase_market.clone(),
+                config.coinbase_channel,
+                coinbase_rp.clone(),
+            );
+            thread::spawn(move || coinbase_feed::start(url, mkt, channel, rp));
+        }
+
+        let okx_rp = Arc::new(Mutex::new(ref_price::RefPrice::new()));
+        if let (true, Some(okx)) = (uses_venue(Venue::Okx), &config.okx) {
+            let (url, symbol, channel, rp) = (okx.ws_url.clone(), okx.symbol.clone(), okx.channel, okx_rp.clone());
+            thread::spawn(move || okx_feed::start(url, symbol, channel, rp));
+        }
+
+        let kraken_rp = Arc::new(Mutex::new(ref_price::RefPrice::new()));
+        if let (true, Some(kraken)) = (uses_venue(Venue::Kraken), &config.kraken) {
+            let (url, symbol, channel, depth, rp) = (
+                kraken.ws_url.clone(),
+                kraken.symbol.clone(),
+                kraken.channel,
+                kraken.depth,
+                kraken_rp.clone(),
+            );
+            thread::spawn(move || kraken_feed::start(url, symbol, channel, depth, rp));
+        }
+
+        let mut fx_rate: Option<Arc<FxRate>> = None;
+        if let Some(fx) = &config.fx {
+            let fx_rp = Arc::new(Mutex::new(ref_price::RefPrice::new()));
+            let source: Arc<dyn PriceSource> = match fx.venue {
+                Venue::Binance => {
+                    let (url, mkt, rp) = (config.binance_ws_url.clone(), fx.market.clone(), fx_rp.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = binance_ws::start(url, mkt, BinanceStream::BookTicker, None, rp) {
+                            error!("binance fx feed stopped: {}", e);
+                        }
+                    });
+                    Arc::new(FeedPriceSource::new("fx-binance", fx_rp.clone()))
+                }
+                Venue::Bybit => {
+                    let (url, category, mkt, rp) =
+                        (config.bybit_ws_url.clone(), config.bybit_category, fx.market.clone(), fx_rp.clone());
+                    thread::spawn(move || bybit_feed::start_ws(url, category, mkt, 1, rp));
+                    Arc::new(FeedPriceSource::new("fx-bybit", fx_rp.clone()))
+                }
+                Venue::Coinbase => {
+                    let (url, mkt, rp) = (config.coinbase_ws_url.clone(), fx.market.clone(), fx_rp.clone());
+                    thread::spawn(move || coinbase_feed::start(url, mkt, CoinbaseChannel::Ticker, rp));
+                    Arc::new(FeedPriceSource::new("fx-coinbase", fx_rp.clone()))
+                }
+                _ => unreachable!("fx venue validated in config_validation"),
+            };
+            fx_rate = Some(Arc::new(FxRate { source, invert: fx.invert }));
+        }
+
+        let mut sources: Vec<WeightedSource> = vec![];
+        for s in ref_sources.iter() {
+            let source: Arc<dyn PriceSource> = match s.venue {
+                Venue::Binance => Arc::new(FeedPriceSource::new("binance", binance_rp.clone())),
+                Venue::Bybit => Arc::new(FeedPriceSource::new("bybit", bybit_rp.clone())),
+                Venue::Coinbase => Arc::new(FeedPriceSource::new("coinbase", coinbase_rp.clone())),
+                Venue::Okx => Arc::new(FeedPriceSource::new("okx", okx_rp.clone())),
+                Venue::Kraken => Arc::new(FeedPriceSource::new("kraken", kraken_rp.clone())),
+                Venue::Vega => Arc::new(VegaPriceSource::new(vstore.clone(), VegaPriceKind::BestBidAsk, 0.0)),
+                Venue::VegaMark => Arc::new(VegaPriceSource::new(vstore.clone(), VegaPriceKind::Mark, s.half_spread_bp)),
+                Venue::VegaExternalTwap => {
+                    Arc::new(VegaPriceSource::new(vstore.clone(), VegaPriceKind::ExternalTwap, s.half_spread_bp))
+                }
+                Venue::VegaIndicative => {
+                    Arc::new(VegaPriceSource::new(vstore.clone(), VegaPriceKind::Indicative, s.half_spread_bp))
+                }
+            };
+            // the vega book is already in the market's quote currency
+            let fx = if s.venue.is_vega() { None } else { fx_rate.clone() };
+            sources.push(WeightedSource {
+                source,
+                weight: s.weight,
+                price_scaling: s.price_scaling,
+                fx,
+            });
         }
         
+        let metrics = Arc::new(Mutex::new(metrics::Metrics::new()));
+
         let mut rng = rand::thread_rng();
-        tokio::spawn(strategy2::start(
+        let strategy = tokio::spawn(strategy2::start(
             w1.clone(),
             config.clone(),
             vstore.clone(),
-            binance_rp.clone(),
-            bybit_rp.clone(),
+            pool.clone(),
+            sources,
             old_rp.clone(),
             skip_counter.clone(),
+            metrics.clone(),
         ));
 
 
-        // just loop forever, waiting for user interupt
-        let mut interval = time::interval(Duration::from_secs(1));
-        loop {
-            tokio::select! {
-                _ = interval.tick() => {
-                    interval.reset();
-                }
-            }
-        }
+        // run until interrupted, then take our orders off the book before exiting
+        shutdown::wait_for_signal().await;
+        info!("shutting down, stopping the strategy");
+        strategy.abort();
+        let _ = strategy.await;
+        shutdown::shutdown(w1, config, vstore, pool).await;
+        exit(0);
     }
 
 }
@@ -0,0 +1,30 @@
+use log::info;
+use std::collections::BTreeMap;
+
+// Simple named counters, shared between tasks behind an Arc<Mutex<>> and
+// dumped to the log by the strategy.
+pub struct Metrics {
+    counters: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        return Metrics {
+            counters: BTreeMap::new(),
+        };
+    }
+
+    pub fn incr(&mut self, name: &str) {
+        *self.counters.entry(name.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        return self.counters.get(name).cloned().unwrap_or(0);
+    }
+
+    pub fn log(&self) {
+        for (name, value) in self.counters.iter() {
+            info!("metric {}: {}", name, value);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use futures::future::join_all;
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time;
+use vega_protobufs::datanode::api::v2::{
+    trading_data_service_client::TradingDataServiceClient, GetLatestMarketDataRequest,
+};
+
+use crate::vega_store2::{Error, VegaStore, VegaStream};
+
+// The datanodes we can talk to and the one currently in use. The generation
+// is bumped on every switch so the streams and the transaction sender know
+// they have to reconnect.
+pub struct NodePool {
+    urls: Vec<String>,
+    current: usize,
+    generation: u64,
+}
+
+impl NodePool {
+    pub fn new(urls: Vec<String>, current: usize) -> NodePool {
+        return NodePool {
+            urls,
+            current,
+            generation: 0,
+        };
+    }
+
+    pub fn urls(&self) -> Vec<String> {
+        return self.urls.clone();
+    }
+
+    pub fn current(&self) -> usize {
+        return self.current;
+    }
+
+    pub fn current_url(&self) -> String {
+        return self.urls[self.current].clone();
+    }
+
+    pub fn generation(&self) -> u64 {
+        return self.generation;
+    }
+
+    pub fn switch_to(&mut self, idx: usize) {
+        if idx == self.current {
+            return;
+        }
+        info!("switching datanode from {} to {}", self.urls[self.current], self.urls[idx]);
+        self.current = idx;
+        self.generation += 1;
+    }
+}
+
+// true if the pool moved to another node since `generation`
+pub fn node_switched(pool: &Arc<Mutex<NodePool>>, generation: u64) -> bool {
+    return pool.lock().unwrap().generation() != generation;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeHealth {
+    pub block_height: u64,
+    // vega time of the node's latest market data, in nanos
+    pub market_data_ts: i64,
+}
+
+// datanodes return the block height they are at with every response
+pub async fn probe(url: &str, market: &str) -> Result<NodeHealth, Error> {
+    let mut clt = TradingDataServiceClient::connect(url.to_string()).await?;
+    let resp = clt
+        .get_latest_market_data(GetLatestMarketDataRequest {
+            market_id: market.to_string(),
+        })
+        .await?;
+
+    let block_height = resp
+        .metadata()
+        .get("x-block-height")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    let market_data_ts = resp.get_ref().market_data.as_ref().map(|md| md.timestamp).unwrap_or(0);
+    return Ok(NodeHealth {
+        block_height,
+        market_data_ts,
+    });
+}
+
+// Probes every node at once, each bounded by `timeout` so one hung node
+// can't hold up the others. None for the nodes that didn't answer in time.
+pub async fn probe_all(urls: &Vec<String>, market: &str, timeout: Duration) -> Vec<Option<NodeHealth>> {
+    let probes = urls.iter().map(|url| async move {
+        match time::timeout(timeout, probe(url, market)).await {
+            Ok(Ok(h)) => {
+                info!("datanode {} at block {}", url, h.block_height);
+                return Some(h);
+            }
+            Ok(Err(e)) => {
+                warn!("datanode {} failed the health check: {}", url, e);
+                return None;
+            }
+            Err(_) => {
+                warn!("datanode {} did not answer the health check within {:?}", url, timeout);
+                return None;
+            }
+        }
+    });
+    return join_all(probes).await;
+}
+
+// The node furthest ahead by block height, then by the timestamp of its latest
+// market data. Ties go to the first node in the config.
+pub fn pick_best(health: &Vec<Option<NodeHealth>>, exclude: Option<usize>) -> Option<usize> {
+    let mut best: Option<(usize, &NodeHealth)> = None;
+    for (idx, h) in health.iter().enumerate() {
+        let h = match h {
+            Some(h) if Some(idx) != exclude => h,
+            _ => continue,
+        };
+        let better = match best {
+            None => true,
+            Some((_, b)) => (h.block_height, h.market_data_ts) > (b.block_height, b.market_data_ts),
+        };
+        if better {
+            best = Some((idx, h));
+        }
+    }
+    return best.map(|(idx, _)| idx);
+}
+
+// how many blocks node `idx` is behind the best one, None if it didn't answer
+pub fn block_lag(health: &Vec<Option<NodeHealth>>, idx: usize) -> Option<u64> {
+    let h = health.get(idx)?.as_ref()?;
+    let top = health.iter().flatten().map(|h| h.block_height).max()?;
+    return Some(top.saturating_sub(h.block_height));
+}
+
+// Health checks the datanodes every check_interval, each probe bounded by the
+// interval too, and moves the pool to the best one when the current node is
+// down, lags by more than max_block_lag blocks, or has stopped streaming
+// market data to the store.
+pub async fn monitor(
+    pool: Arc<Mutex<NodePool>>,
+    store: Arc<Mutex<VegaStore>>,
+    market: String,
+    max_block_lag: u64,
+    max_stream_age: Duration,
+    check_interval: Duration,
+) {
+    let urls = pool.lock().unwrap().urls();
+    if urls.len() < 2 {
+        info!("only one datanode configured, no failover");
+        return;
+    }
+
+    let mut interval = time::interval(check_interval);
+    loop {
+        interval.tick().await;
+
+        let health = probe_all(&urls, &market, check_interval).await;
+        let current = pool.lock().unwrap().current();
+        let stalled = match store.lock().unwrap().get_stream_status(VegaStream::MarketData).last_update {
+            Some(t) => t.elapsed() > max_stream_age,
+            None => false,
+        };
+
+        let reason = match block_lag(&health, current) {
+            None => "did not answer the health check".to_string(),
+            Some(lag) if lag > max_block_lag => format!("is {} blocks behind", lag),
+            Some(_) if stalled => "stopped streaming market data".to_string(),
+            Some(_) => continue,
+        };
+
+        // a stalled node can still be the highest one, then try the others
+        let target = match pick_best(&health, None) {
+            Some(best) if best != current => Some(best),
+            _ => pick_best(&health, Some(current)),
+        };
+        match target {
+            Some(t) => {
+                warn!("datanode {} {}", urls[current], reason);
+                pool.lock().unwrap().switch_to(t);
+            }
+            None => warn!("datanode {} {} but no other datanode is healthy", urls[current], reason),
+        }
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    fn h(block_height: u64, market_data_ts: i64) -> Option<NodeHealth> {
+        return Some(NodeHealth {
+            block_height,
+            market_data_ts,
+        });
+    }
+
+    #[test]
+    fn test_pick_best() {
+        let health = vec![h(100, 5), None, h(102, 1), h(102, 3)];
+        assert_eq!(pick_best(&health, None), Some(3));
+        assert_eq!(pick_best(&health, Some(3)), Some(2));
+        assert_eq!(pick_best(&vec![None, None], None), None);
+        // ties go to the first node
+        assert_eq!(pick_best(&vec![h(7, 7), h(7, 7)], None), Some(0));
+    }
+
+    #[test]
+    fn test_block_lag() {
+        let health = vec![h(100, 0), None, h(112, 0)];
+        assert_eq!(block_lag(&health, 0), Some(12));
+        assert_eq!(block_lag(&health, 1), None);
+        assert_eq!(block_lag(&health, 2), Some(0));
+    }
+
+    #[test]
+    fn test_switch_bumps_generation() {
+        let pool = Arc::new(Mutex::new(NodePool::new(vec!["a".to_string(), "b".to_string()], 0)));
+        pool.lock().unwrap().switch_to(0);
+        assert!(!node_switched(&pool, 0));
+        pool.lock().unwrap().switch_to(1);
+        assert!(node_switched(&pool, 0));
+        assert_eq!(pool.lock().unwrap().current_url(), "b");
+    }
+}
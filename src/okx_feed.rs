@@ -0,0 +1,246 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tungstenite::{connect, Message};
+
+use crate::backoff::Backoff;
+use crate::ref_price::{Book, BookSide, RefPrice};
+use crate::ws_util;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OkxChannel {
+    // best bid / ask with sizes
+    Tickers,
+    // 5 levels of depth, full snapshot on every push
+    Books5,
+}
+
+impl Default for OkxChannel {
+    fn default() -> Self {
+        OkxChannel::Tickers
+    }
+}
+
+impl OkxChannel {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OkxChannel::Tickers => "tickers",
+            OkxChannel::Books5 => "books5",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct WsArg {
+    channel: String,
+    #[serde(rename = "instId")]
+    inst_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct WsMessage {
+    arg: Option<WsArg>,
+    // subscribe acks and errors
+    event: Option<String>,
+    data: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TickerData {
+    #[serde(rename = "bidPx")]
+    bid_px: String,
+    #[serde(rename = "bidSz")]
+    bid_sz: String,
+    #[serde(rename = "askPx")]
+    ask_px: String,
+    #[serde(rename = "askSz")]
+    ask_sz: String,
+    ts: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BookData {
+    // [price, size, deprecated, number of orders]
+    asks: Vec<Vec<String>>,
+    bids: Vec<Vec<String>>,
+    ts: String,
+}
+
+// OKX public websocket feed, reconnects with backoff forever.
+pub fn start(ws_url: String, inst_id: String, channel: OkxChannel, rp: Arc<Mutex<RefPrice>>) {
+    ws_util::reconnect_forever("okx", |backoff| run(&ws_url, &inst_id, channel, rp.clone(), backoff));
+}
+
+// okx drops connections with no traffic for 30 seconds, it wants a plain
+// text "ping" and answers with "pong"
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const MAX_SILENCE: Duration = Duration::from_secs(60);
+
+fn run(url: &str, inst_id: &str, channel: OkxChannel, rp: Arc<Mutex<RefPrice>>, backoff: &mut Backoff) -> Result<(), tungstenite::Error> {
+    info!("opening websocket with okx API at: {}", url);
+    let (mut socket, _) = connect(url)?;
+    info!("connected to okx successfully");
+    ws_util::set_read_timeout(&mut socket, Some(Duration::from_secs(5)))?;
+
+    let request = serde_json::json!({
+        "op": "subscribe",
+        "args": [{"channel": channel.as_str(), "instId": inst_id}],
+    });
+    socket.write_message(Message::Text(request.to_string()))?;
+
+    let mut last_ping = Instant::now();
+    let mut last_msg = Instant::now();
+    loop {
+        if last_ping.elapsed() >= PING_INTERVAL {
+            socket.write_message(Message::Text("ping".to_string()))?;
+            last_ping = Instant::now();
+        }
+        if last_msg.elapsed() >= MAX_SILENCE {
+            return Err(ws_util::timeout_error("no message from okx, not even a pong"));
+        }
+
+        let msg = match socket.read_message() {
+            Ok(m) => m,
+            Err(e) if ws_util::is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        last_msg = Instant::now();
+
+        let text = match msg {
+            Message::Text(t) => t,
+            Message::Close(frame) => {
+                info!("okx sent close frame: {:?}", frame);
+                return Ok(());
+            }
+            _ => continue,
+        };
+
+        if apply_message(&text, inst_id, &rp) {
+            backoff.reset();
+        }
+    }
+}
+
+fn parse_levels(book: &mut Book, side: BookSide, levels: &Vec<Vec<String>>) {
+    for level in levels.iter() {
+        let parsed = match (level.get(0), level.get(1)) {
+            (Some(p), Some(s)) => (p.parse::<f64>(), s.parse::<f64>()),
+            _ => {
+                warn!("skipping short okx level: {:?}", level);
+                continue;
+            }
+        };
+        match parsed {
+            (Ok(price), Ok(size)) => book.set_level(side, price, size),
+            _ => warn!("skipping bad okx level: {:?}", level),
+        }
+    }
+}
+
+// Returns true if the message updated the reference price. Pongs, subscribe
+// acks and anything malformed are skipped.
+fn apply_message(text: &str, inst_id: &str, rp: &Arc<Mutex<RefPrice>>) -> bool {
+    if text == "pong" {
+        return false;
+    }
+    let msg = match serde_json::from_str::<WsMessage>(text) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("skipping malformed okx message: {} ({})", text, e);
+            return false;
+        }
+    };
+    if msg.event.as_deref() == Some("error") {
+        error!("okx error: {}", text);
+        return false;
+    }
+
+    let (arg, data) = match (msg.arg, msg.data) {
+        (Some(arg), Some(data)) if arg.inst_id == inst_id => (arg, data),
+        _ => return false,
+    };
+    let last = match data.into_iter().last() {
+        Some(d) => d,
+        None => return false,
+    };
+
+    match arg.channel.as_str() {
+        "tickers" => {
+            let t = match serde_json::from_value::<TickerData>(last) {
+                Ok(t) => t,
+                Err(e) => {
+                    warn!("skipping bad okx ticker: {}", e);
+                    return false;
+                }
+            };
+            let top = (t.bid_px.parse::<f64>(), t.bid_sz.parse::<f64>(), t.ask_px.parse::<f64>(), t.ask_sz.parse::<f64>());
+            match top {
+                (Ok(bid), Ok(bid_size), Ok(ask), Ok(ask_size)) if bid > 0.0 && ask > 0.0 => {
+                    let ts = t.ts.parse::<u64>().unwrap_or(0);
+                    rp.lock().unwrap().set_top(bid, bid_size, ask, ask_size, ts);
+                    return true;
+                }
+                // empty bidPx / askPx when one side of the book is empty
+                _ => {
+                    warn!("skipping okx ticker without best bid / ask: {:?}", t);
+                    return false;
+                }
+            }
+        }
+        "books5" => {
+            let b = match serde_json::from_value::<BookData>(last) {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("skipping bad okx book: {}", e);
+                    return false;
+                }
+            };
+            let mut book = Book::new();
+            parse_levels(&mut book, BookSide::Bid, &b.bids);
+            parse_levels(&mut book, BookSide::Ask, &b.asks);
+            return rp.lock().unwrap().set_book(book, b.ts.parse::<u64>().unwrap_or(0));
+        }
+        _ => return false,
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_ticker() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let ticker = r#"{"arg":{"channel":"tickers","instId":"VEGA-USDT"},"data":[{"instType":"SPOT","instId":"VEGA-USDT","last":"0.4711","lastSz":"120.5","askPx":"0.4712","askSz":"830.1","bidPx":"0.4708","bidSz":"1500","open24h":"0.46","high24h":"0.48","low24h":"0.455","sodUtc0":"0.465","sodUtc8":"0.462","volCcy24h":"250000","vol24h":"530000","ts":"1714644000123"}]}"#;
+        assert!(apply_message(ticker, "VEGA-USDT", &rp));
+        let rp = rp.lock().unwrap();
+        assert_eq!(rp.get(), (0.4708, 0.4712));
+        assert_eq!(rp.sizes(), (1500.0, 830.1));
+        assert_eq!(rp.exchange_ts(), 1714644000123);
+    }
+
+    #[test]
+    fn test_apply_books5() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let book = r#"{"arg":{"channel":"books5","instId":"BTC-USDT-SWAP"},"data":[{"asks":[["59001.1","12","0","3"],["59001.5","40","0","5"]],"bids":[["59000.9","7","0","2"],["59000.2","25","0","4"]],"instId":"BTC-USDT-SWAP","ts":"1714644000456","seqId":123456}]}"#;
+        assert!(apply_message(book, "BTC-USDT-SWAP", &rp));
+        let rp = rp.lock().unwrap();
+        assert_eq!(rp.get(), (59000.9, 59001.1));
+        assert_eq!(rp.book().bids(), &vec![(59000.9, 7.0), (59000.2, 25.0)]);
+    }
+
+    #[test]
+    fn test_apply_skips_junk() {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let ack = r#"{"event":"subscribe","arg":{"channel":"tickers","instId":"VEGA-USDT"},"connId":"a4d3ae55"}"#;
+        let err = r#"{"event":"error","code":"60018","msg":"Wrong URL or channel:tickers,instId:FOO-BAR doesn't exist.","connId":"a4d3ae55"}"#;
+        let one_sided = r#"{"arg":{"channel":"tickers","instId":"VEGA-USDT"},"data":[{"instId":"VEGA-USDT","askPx":"0.4712","askSz":"830.1","bidPx":"","bidSz":"","ts":"1"}]}"#;
+        assert!(!apply_message("pong", "VEGA-USDT", &rp));
+        assert!(!apply_message(ack, "VEGA-USDT", &rp));
+        assert!(!apply_message(err, "VEGA-USDT", &rp));
+        assert!(!apply_message(one_sided, "VEGA-USDT", &rp));
+        assert_eq!(rp.lock().unwrap().get(), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,188 @@
+use log::info;
+use std::collections::HashMap;
+use vega_protobufs::vega::commands::v1::{BatchMarketInstructions, OrderAmendment, OrderCancellation, OrderSubmission};
+use vega_protobufs::vega::{Order, Side};
+
+// Our ladder orders carry a reference naming their slot, e.g. mm-buy-0 for the
+// best bid, so we can match what we want on the book with what is there.
+pub fn ladder_reference(side: Side, level: u64) -> String {
+    let side = match side {
+        Side::Buy => "buy",
+        _ => "sell",
+    };
+    return format!("mm-{}-{}", side, level);
+}
+
+// Turns the desired ladder into the smallest batch that gets our live orders
+// there: amendments for slots whose price or size moved, cancels for live
+// orders that have no slot any more (or share one with another order) and
+// submissions for empty slots. Unchanged orders are left alone so they keep
+// their queue priority, unless they expire before refresh_before, then only
+// their expiry is pushed out.
+pub fn diff_orders(
+    market_id: &str,
+    desired: Vec<OrderSubmission>,
+    mut live: Vec<Order>,
+    refresh_before: i64,
+) -> BatchMarketInstructions {
+    // oldest first so duplicates of a slot are the ones we cancel
+    live.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+
+    let mut by_reference: HashMap<String, Order> = HashMap::new();
+    let mut cancellations: Vec<OrderCancellation> = vec![];
+    for o in live.into_iter() {
+        if o.reference.is_empty() || by_reference.contains_key(&o.reference) {
+            cancellations.push(cancel(market_id, &o));
+            continue;
+        }
+        by_reference.insert(o.reference.clone(), o);
+    }
+
+    let mut amendments: Vec<OrderAmendment> = vec![];
+    let mut submissions: Vec<OrderSubmission> = vec![];
+    for want in desired.into_iter() {
+        let have = match by_reference.remove(&want.reference) {
+            Some(o) => o,
+            None => {
+                submissions.push(want);
+                continue;
+            }
+        };
+        if have.side != want.side {
+            cancellations.push(cancel(market_id, &have));
+            submissions.push(want);
+            continue;
+        }
+
+        let price_changed = have.price != want.price;
+        let size_delta = want.size as i64 - have.remaining as i64;
+        let needs_refresh = have.expires_at < refresh_before;
+        if !price_changed && size_delta == 0 && !needs_refresh {
+            continue;
+        }
+
+        amendments.push(OrderAmendment {
+            order_id: have.id.clone(),
+            market_id: market_id.to_string(),
+            price: if price_changed { Some(want.price.clone()) } else { None },
+            size_delta,
+            expires_at: Some(want.expires_at),
+            ..Default::default()
+        });
+    }
+
+    // whatever is left has no slot in the new ladder
+    let mut leftover: Vec<Order> = by_reference.into_values().collect();
+    leftover.sort_by(|a, b| a.id.cmp(&b.id));
+    for o in leftover.iter() {
+        cancellations.push(cancel(market_id, o));
+    }
+
+    info!(
+        "order diff: {} amendments, {} cancellations, {} submissions",
+        amendments.len(),
+        cancellations.len(),
+        submissions.len()
+    );
+    return BatchMarketInstructions {
+        cancellations,
+        amendments,
+        submissions,
+        stop_orders_cancellation: vec![],
+        stop_orders_submission: vec![],
+        update_margin_mode: vec![],
+    };
+}
+
+fn cancel(market_id: &str, o: &Order) -> OrderCancellation {
+    return OrderCancellation {
+        order_id: o.id.clone(),
+        market_id: market_id.to_string(),
+    };
+}
+
+pub fn is_empty(batch: &BatchMarketInstructions) -> bool {
+    return batch.cancellations.is_empty() && batch.amendments.is_empty() && batch.submissions.is_empty();
+}
+
+
+mod tests {
+    use super::*;
+
+    fn want(side: Side, level: u64, price: u64, size: u64) -> OrderSubmission {
+        return OrderSubmission {
+            market_id: "m".to_string(),
+            price: price.to_string(),
+            size,
+            side: side.into(),
+            reference: ladder_reference(side, level),
+            expires_at: 2_000,
+            ..Default::default()
+        };
+    }
+
+    fn have(id: &str, side: Side, level: u64, price: u64, remaining: u64) -> Order {
+        return Order {
+            id: id.to_string(),
+            market_id: "m".to_string(),
+            price: price.to_string(),
+            size: remaining,
+            remaining,
+            side: side.into(),
+            reference: ladder_reference(side, level),
+            expires_at: 1_800,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_unchanged_ladder_sends_nothing() {
+        let desired = vec![want(Side::Buy, 0, 99, 10), want(Side::Sell, 0, 101, 10)];
+        let live = vec![have("a", Side::Buy, 0, 99, 10), have("b", Side::Sell, 0, 101, 10)];
+        assert!(is_empty(&diff_orders("m", desired, live, 1_500)));
+    }
+
+    #[test]
+    fn test_diff() {
+        let desired = vec![
+            // price moved
+            want(Side::Buy, 0, 98, 10),
+            // partially filled, top it back up
+            want(Side::Buy, 1, 97, 20),
+            // new slot
+            want(Side::Sell, 0, 101, 10),
+        ];
+        let mut dup = have("d", Side::Buy, 1, 97, 20);
+        dup.created_at = 5;
+        let live = vec![
+            have("a", Side::Buy, 0, 99, 10),
+            have("b", Side::Buy, 1, 97, 15),
+            dup,
+            have("c", Side::Buy, 2, 96, 10),
+        ];
+        let batch = diff_orders("m", desired, live, 1_500);
+
+        assert_eq!(batch.amendments.len(), 2);
+        assert_eq!(batch.amendments[0].order_id, "a");
+        assert_eq!(batch.amendments[0].price, Some("98".to_string()));
+        assert_eq!(batch.amendments[0].size_delta, 0);
+        assert_eq!(batch.amendments[1].order_id, "b");
+        assert_eq!(batch.amendments[1].price, None);
+        assert_eq!(batch.amendments[1].size_delta, 5);
+
+        let cancelled: Vec<String> = batch.cancellations.iter().map(|c| c.order_id.clone()).collect();
+        assert_eq!(cancelled, vec!["d", "c"]);
+        assert_eq!(batch.submissions.len(), 1);
+        assert_eq!(batch.submissions[0].reference, "mm-sell-0");
+    }
+
+    #[test]
+    fn test_refreshes_expiry() {
+        let desired = vec![want(Side::Buy, 0, 99, 10)];
+        let live = vec![have("a", Side::Buy, 0, 99, 10)];
+        let batch = diff_orders("m", desired, live, 1_900);
+        assert_eq!(batch.amendments.len(), 1);
+        assert_eq!(batch.amendments[0].price, None);
+        assert_eq!(batch.amendments[0].expires_at, Some(2_000));
+    }
+}
@@ -0,0 +1,336 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use log::info;
+use vega_protobufs::vega::{MarketData, Position, Side};
+
+use crate::fills::Fill;
+
+// Everything in asset units (not asset decimals).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PnlTotals {
+    // from closing positions against our average entry, fees not included
+    pub realised: f64,
+    pub fees_paid: f64,
+    pub maker_fees: f64,
+    pub lp_fees: f64,
+    // perps only, positive if we received it
+    pub funding: f64,
+}
+
+impl PnlTotals {
+    // realised P&L plus everything we received less what we paid
+    pub fn net(&self) -> f64 {
+        return self.realised - self.fees_paid + self.maker_fees + self.lp_fees + self.funding;
+    }
+}
+
+// How far the positions stream is from our own numbers, positive if vega
+// has more. Realised is compared as the change since our session started.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PnlDrift {
+    pub position: f64,
+    pub realised: f64,
+    pub unrealised: f64,
+}
+
+impl PnlDrift {
+    pub fn exceeds(&self, max_pnl_drift: f64, position_factor: f64) -> bool {
+        // the position is in contracts, anything below one lot is rounding
+        return self.position.abs() >= 1.0 / position_factor
+            || self.realised.abs() > max_pnl_drift
+            || self.unrealised.abs() > max_pnl_drift;
+    }
+}
+
+// Our P&L for the session and for the current UTC day, built from our fills
+// and the mark price. Prices are in quote units and the position in contracts.
+pub struct PnlEngine {
+    price_factor: f64,
+    position_factor: f64,
+    asset_factor: f64,
+    position: f64,
+    avg_entry: f64,
+    mark: f64,
+    session: PnlTotals,
+    today: PnlTotals,
+    day: Option<NaiveDate>,
+    // seq of the last fill applied
+    fills_cursor: u64,
+    lp_fees_balance: Option<f64>,
+    // perps: sequence number of the funding period in flight and its payment
+    // per contract so far, in asset decimals
+    funding_period: Option<(u64, f64)>,
+    // vega's realised P&L for our position when we first saw it, None until then
+    vega_realised_start: Option<f64>,
+}
+
+impl PnlEngine {
+    pub fn new(price_factor: f64, position_factor: f64, asset_factor: f64) -> PnlEngine {
+        return PnlEngine {
+            price_factor,
+            position_factor,
+            asset_factor,
+            position: 0.0,
+            avg_entry: 0.0,
+            mark: 0.0,
+            session: PnlTotals::default(),
+            today: PnlTotals::default(),
+            day: None,
+            fills_cursor: 0,
+            lp_fees_balance: None,
+            funding_period: None,
+            vega_realised_start: None,
+        };
+    }
+
+    pub fn position(&self) -> f64 {
+        return self.position;
+    }
+
+    pub fn avg_entry(&self) -> f64 {
+        return self.avg_entry;
+    }
+
+    pub fn mark(&self) -> f64 {
+        return self.mark;
+    }
+
+    pub fn session(&self) -> PnlTotals {
+        return self.session.clone();
+    }
+
+    pub fn today(&self) -> PnlTotals {
+        return self.today.clone();
+    }
+
+    pub fn unrealised(&self) -> f64 {
+        if self.mark <= 0.0 || self.position == 0.0 {
+            return 0.0;
+        }
+        return self.position * (self.mark - self.avg_entry);
+    }
+
+    // the fills saved after this one are the ones to apply next
+    pub fn fills_cursor(&self) -> u64 {
+        return self.fills_cursor;
+    }
+
+    pub fn apply_fill(&mut self, f: &Fill) {
+        self.fills_cursor = self.fills_cursor.max(f.seq);
+        self.roll_day(f.timestamp);
+
+        let size = f.size as f64 / self.position_factor;
+        let qty = match f.side {
+            Side::Buy => size,
+            _ => -size,
+        };
+        self.trade(qty, f.price / self.price_factor);
+
+        let fee = f.fee / self.asset_factor;
+        for t in [&mut self.session, &mut self.today] {
+            if fee >= 0.0 {
+                t.fees_paid += fee;
+            } else {
+                t.maker_fees -= fee;
+            }
+        }
+    }
+
+    fn trade(&mut self, qty: f64, price: f64) {
+        // adding to the position (or opening one) only moves the average entry
+        if self.position == 0.0 || self.position.signum() == qty.signum() {
+            let size = self.position.abs() + qty.abs();
+            self.avg_entry = (self.avg_entry * self.position.abs() + price * qty.abs()) / size;
+            self.position += qty;
+            return;
+        }
+
+        let closed = qty.abs().min(self.position.abs());
+        let realised = closed * (price - self.avg_entry) * self.position.signum();
+        for t in [&mut self.session, &mut self.today] {
+            t.realised += realised;
+        }
+        let was_long = self.position > 0.0;
+        self.position += qty;
+        if self.position.abs() < 1e-12 {
+            self.position = 0.0;
+            self.avg_entry = 0.0;
+        } else if (self.position > 0.0) != was_long {
+            // flipped, what's left was opened at this price
+            self.avg_entry = price;
+        }
+    }
+
+    pub fn apply_market_data(&mut self, md: &MarketData) {
+        use vega_protobufs::vega::product_data::Data;
+
+        self.roll_day(md.timestamp as u64);
+        let mark = md.mark_price.parse::<f64>().unwrap_or_default();
+        if mark > 0.0 {
+            self.mark = mark / self.price_factor;
+        }
+        if let Some(Data::PerpetualData(perp)) = md.product_data.as_ref().and_then(|p| p.data.as_ref()) {
+            self.update_funding(perp.seq_num, perp.funding_payment.parse::<f64>().unwrap_or_default());
+        }
+    }
+
+    // The payment of a funding period is settled when the next one starts,
+    // longs pay a positive payment to shorts.
+    fn update_funding(&mut self, seq_num: u64, payment: f64) {
+        if let Some((prev_seq, prev_payment)) = self.funding_period {
+            if seq_num != prev_seq {
+                let funding = -prev_payment / self.asset_factor * self.position;
+                info!("funding period {} settled, our funding: {}", prev_seq, funding);
+                for t in [&mut self.session, &mut self.today] {
+                    t.funding += funding;
+                }
+            }
+        }
+        self.funding_period = Some((seq_num, payment));
+    }
+
+    // The LP fee account is paid out into the general account at the end of
+    // every epoch, so we count its increases only.
+    pub fn update_lp_fees(&mut self, balance: f64) {
+        let balance = balance / self.asset_factor;
+        if let Some(prev) = self.lp_fees_balance {
+            if balance > prev {
+                for t in [&mut self.session, &mut self.today] {
+                    t.lp_fees += balance - prev;
+                }
+            }
+        }
+        self.lp_fees_balance = Some(balance);
+    }
+
+    fn roll_day(&mut self, timestamp: u64) {
+        let day = NaiveDateTime::from_timestamp(
+            (timestamp / 1_000_000_000) as i64,
+            (timestamp % 1_000_000_000) as u32,
+        )
+        .date();
+        match self.day {
+            Some(d) if d >= day => return,
+            Some(d) => info!("P&L of {}: net {:.4}, {:?}", d, self.today.net(), self.today),
+            None => {}
+        }
+        self.today = PnlTotals::default();
+        self.day = Some(day);
+    }
+
+    // Compares our numbers with the positions stream. The first position we
+    // see takes over our position and average entry, its realised P&L is the
+    // baseline for the session; None then.
+    pub fn reconcile(&mut self, p: &Position) -> Option<PnlDrift> {
+        let vega_position = p.open_volume as f64 / self.position_factor;
+        let vega_realised = p.realised_pnl.parse::<f64>().unwrap_or_default() / self.asset_factor;
+        let vega_unrealised = p.unrealised_pnl.parse::<f64>().unwrap_or_default() / self.asset_factor;
+
+        let start = match self.vega_realised_start {
+            Some(s) => s,
+            None => {
+                self.position = vega_position;
+                self.avg_entry = p.average_entry_price.parse::<f64>().unwrap_or_default() / self.price_factor;
+                self.vega_realised_start = Some(vega_realised - self.session.realised - self.session.funding);
+                return None;
+            }
+        };
+
+        // vega books funding payments as realised P&L
+        return Some(PnlDrift {
+            position: vega_position - self.position,
+            realised: (vega_realised - start) - (self.session.realised + self.session.funding),
+            unrealised: vega_unrealised - self.unrealised(),
+        });
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    fn fill(id: &str, side: Side, price: f64, size: u64, fee: f64) -> Fill {
+        return Fill {
+            trade_id: id.to_string(),
+            order_id: "o".to_string(),
+            timestamp: 1_700_000_000_000_000_000,
+            side,
+            price,
+            size,
+            fee,
+            is_maker: fee < 0.0,
+            block_best_bid: 0.0,
+            block_best_ask: 0.0,
+            seq: id.parse().unwrap(),
+        };
+    }
+
+    fn engine() -> PnlEngine {
+        // 1 price decimal, no position decimals, 2 asset decimals
+        return PnlEngine::new(10.0, 1.0, 100.0);
+    }
+
+    #[test]
+    fn test_average_entry_and_realised() {
+        let mut e = engine();
+        e.apply_fill(&fill("1", Side::Buy, 1000.0, 2, 10.0));
+        e.apply_fill(&fill("2", Side::Buy, 1030.0, 1, -5.0));
+        assert!((e.avg_entry() - 101.0).abs() < 1e-9);
+        assert_eq!(e.position(), 3.0);
+        assert_eq!(e.fills_cursor(), 2);
+
+        // sell 4 at 105: close 3 for +12, short 1 from 105
+        e.apply_fill(&fill("3", Side::Sell, 1050.0, 4, 0.0));
+        assert!((e.session().realised - 12.0).abs() < 1e-9);
+        assert_eq!(e.position(), -1.0);
+        assert_eq!(e.avg_entry(), 105.0);
+        assert_eq!(e.session().fees_paid, 0.1);
+        assert_eq!(e.session().maker_fees, 0.05);
+
+        e.mark = 104.0;
+        assert_eq!(e.unrealised(), 1.0);
+        assert_eq!(e.today(), e.session());
+    }
+
+    #[test]
+    fn test_funding_and_lp_fees() {
+        let mut e = engine();
+        e.apply_fill(&fill("1", Side::Sell, 1000.0, 2, 0.0));
+        e.update_funding(1, 50.0);
+        e.update_funding(1, 150.0);
+        assert_eq!(e.session().funding, 0.0);
+        // shorts receive a positive payment
+        e.update_funding(2, 0.0);
+        assert_eq!(e.session().funding, 3.0);
+
+        e.update_lp_fees(100.0);
+        e.update_lp_fees(300.0);
+        // paid out to the general account
+        e.update_lp_fees(0.0);
+        e.update_lp_fees(50.0);
+        assert_eq!(e.session().lp_fees, 2.5);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let mut e = engine();
+        let position = |open_volume: i64, realised: &str| Position {
+            open_volume,
+            realised_pnl: realised.to_string(),
+            unrealised_pnl: "0".to_string(),
+            average_entry_price: "1000".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(e.reconcile(&position(2, "500")), None);
+        assert_eq!(e.avg_entry(), 100.0);
+
+        e.apply_fill(&fill("1", Side::Sell, 1010.0, 2, 0.0));
+        assert_eq!(
+            e.reconcile(&position(0, "700")),
+            Some(PnlDrift { position: 0.0, realised: 0.0, unrealised: 0.0 })
+        );
+        let drift = e.reconcile(&position(0, "1700")).unwrap();
+        assert_eq!(drift.realised, 10.0);
+        assert!(drift.exceeds(1.0, 1.0));
+        assert!(!e.reconcile(&position(0, "700")).unwrap().exceeds(1.0, 1.0));
+    }
+}
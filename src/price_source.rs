@@ -0,0 +1,451 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ref_price::{BookSide, RefPrice};
+
+// Anything that can give us a reference bid/ask: the external exchange feeds
+// and the Vega store itself. Prices are in real units, not market decimals.
+pub trait PriceSource: Send + Sync {
+    fn name(&self) -> String;
+
+    // latest quote, None if the source has nothing usable yet
+    fn latest(&self) -> Option<RefPrice>;
+}
+
+// An exchange feed: the feed thread writes into rp and we read it from here.
+pub struct FeedPriceSource {
+    name: String,
+    rp: Arc<Mutex<RefPrice>>,
+}
+
+impl FeedPriceSource {
+    pub fn new(name: &str, rp: Arc<Mutex<RefPrice>>) -> FeedPriceSource {
+        return FeedPriceSource { name: name.to_string(), rp };
+    }
+}
+
+impl PriceSource for FeedPriceSource {
+    fn name(&self) -> String {
+        return self.name.clone();
+    }
+
+    fn latest(&self) -> Option<RefPrice> {
+        let rp = self.rp.lock().unwrap().clone();
+        let (bid, ask) = rp.get();
+        if bid <= 0.0 || ask <= 0.0 {
+            return None;
+        }
+        return Some(rp);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Venue {
+    Binance,
+    Bybit,
+    Coinbase,
+    Okx,
+    Kraken,
+    // vega best bid / ask
+    Vega,
+    // vega mark, perpetual external (oracle) TWAP and auction indicative
+    // prices, quoted around with the source's half_spread_bp
+    VegaMark,
+    VegaExternalTwap,
+    VegaIndicative,
+}
+
+impl Venue {
+    pub fn is_vega(&self) -> bool {
+        match self {
+            Venue::Vega | Venue::VegaMark | Venue::VegaExternalTwap | Venue::VegaIndicative => true,
+            _ => false,
+        }
+    }
+
+    // sources that are a single price rather than a bid / ask
+    pub fn needs_half_spread(&self) -> bool {
+        match self {
+            Venue::VegaMark | Venue::VegaExternalTwap | Venue::VegaIndicative => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    // max ask and min bid across sources
+    Widest,
+    // weighted average of bids and of asks
+    WeightedMid,
+    // median of bids and of asks
+    Median,
+}
+
+impl Default for Aggregation {
+    fn default() -> Self {
+        Aggregation::Widest
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefPriceMode {
+    // raw best bid / ask
+    Top,
+    // volume weighted price to fill a given notional on each side, falls back
+    // to the top of book for sources without depth
+    Vwap,
+}
+
+impl Default for RefPriceMode {
+    fn default() -> Self {
+        RefPriceMode::Top
+    }
+}
+
+// bid / ask of a source according to the configured mode
+pub fn source_bid_ask(rp: &RefPrice, mode: RefPriceMode, notional: f64) -> (f64, f64) {
+    let (best_bid, best_ask) = rp.get();
+    match mode {
+        RefPriceMode::Top => return (best_bid, best_ask),
+        RefPriceMode::Vwap => {
+            let bid = rp.vwap_for_notional(BookSide::Bid, notional).unwrap_or(best_bid);
+            let ask = rp.vwap_for_notional(BookSide::Ask, notional).unwrap_or(best_ask);
+            return (bid, ask);
+        }
+    }
+}
+
+// Quote currency conversion rate taken from the mid of an fx feed, e.g. a
+// USDT/USD ticker so that USDT books can be used to quote USD markets.
+pub struct FxRate {
+    pub source: Arc<dyn PriceSource>,
+    // set if the feed quotes the rate the other way round, e.g. USDCUSDT
+    pub invert: bool,
+}
+
+impl FxRate {
+    // None while the fx feed is stale or hasn't sent anything
+    pub fn rate(&self, max_age: Duration) -> Option<f64> {
+        let rp = self.source.latest()?;
+        if rp.is_stale(max_age) {
+            return None;
+        }
+        let (bid, ask) = rp.get();
+        let mid = 0.5 * (bid + ask);
+        if mid <= 0.0 {
+            return None;
+        }
+        if self.invert {
+            return Some(1.0 / mid);
+        }
+        return Some(mid);
+    }
+}
+
+pub struct WeightedSource {
+    pub source: Arc<dyn PriceSource>,
+    pub weight: f64,
+    // static scaling, also the fallback when the fx rate is stale
+    pub price_scaling: f64,
+    pub fx: Option<Arc<FxRate>>,
+}
+
+impl WeightedSource {
+    pub fn scaling(&self, max_age: Duration) -> f64 {
+        let fx = match &self.fx {
+            Some(fx) => fx,
+            None => return self.price_scaling,
+        };
+        match fx.rate(max_age) {
+            Some(rate) => return rate,
+            None => {
+                info!(
+                    "{} rate for {} is stale, falling back to static price scaling {}",
+                    fx.source.name(),
+                    self.source.name(),
+                    self.price_scaling
+                );
+                return self.price_scaling;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceQuote {
+    pub name: String,
+    pub bid: f64,
+    pub ask: f64,
+    pub weight: f64,
+    // book imbalance in [-1, 1] for the fair value model, None if not known
+    pub imbalance: Option<f64>,
+}
+
+pub fn aggregate(quotes: &Vec<SourceQuote>, mode: Aggregation) -> Option<(f64, f64)> {
+    if quotes.is_empty() {
+        return None;
+    }
+
+    match mode {
+        Aggregation::Widest => {
+            let bid = quotes.iter().map(|q| q.bid).fold(f64::MAX, f64::min);
+            let ask = quotes.iter().map(|q| q.ask).fold(f64::MIN, f64::max);
+            return Some((bid, ask));
+        }
+        Aggregation::WeightedMid => {
+            let total_weight: f64 = quotes.iter().map(|q| q.weight).sum();
+            if total_weight <= 0.0 {
+                return None;
+            }
+            let bid = quotes.iter().map(|q| q.weight * q.bid).sum::<f64>() / total_weight;
+            let ask = quotes.iter().map(|q| q.weight * q.ask).sum::<f64>() / total_weight;
+            return Some((bid, ask));
+        }
+        Aggregation::Median => {
+            let bid = median(quotes.iter().map(|q| q.bid).collect());
+            let ask = median(quotes.iter().map(|q| q.ask).collect());
+            return Some((bid, ask));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    // bid >= ask
+    Crossed,
+    // mid too far from the median mid of the other sources, in basis points
+    Outlier(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub name: String,
+    pub reason: RejectReason,
+}
+
+// Cross venue sanity check run before aggregation. Crossed books are always
+// rejected. A venue is an outlier if its mid is more than max_deviation_bp
+// away from the median mid of the other venues, so a bad venue can't pull
+// the median it is compared against towards itself. As one bad venue also
+// drags the others' medians along, only the worst venue is dropped at a time
+// and the rest are checked again without it.
+// With two venues left that disagree we can't tell which one is wrong, both
+// are rejected and the strategy pulls its quotes. A single venue has nothing
+// to be compared against and is kept. max_deviation_bp <= 0 turns the
+// outlier check off.
+pub fn reject_outliers(quotes: Vec<SourceQuote>, max_deviation_bp: f64) -> (Vec<SourceQuote>, Vec<Rejection>) {
+    let mut rejected: Vec<Rejection> = vec![];
+    let mut sane: Vec<SourceQuote> = vec![];
+    for q in quotes.into_iter() {
+        if q.bid >= q.ask {
+            rejected.push(Rejection { name: q.name.clone(), reason: RejectReason::Crossed });
+        } else {
+            sane.push(q);
+        }
+    }
+
+    if max_deviation_bp <= 0.0 {
+        return (sane, rejected);
+    }
+
+    let mut accepted = sane;
+    while accepted.len() >= 2 {
+        let mids: Vec<f64> = accepted.iter().map(|q| 0.5 * (q.bid + q.ask)).collect();
+        let deviations: Vec<f64> = (0..mids.len())
+            .map(|i| {
+                let others = mids.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, m)| *m).collect();
+                let median_mid = median(others);
+                10_000.0 * (mids[i] - median_mid).abs() / median_mid
+            })
+            .collect();
+
+        if accepted.len() == 2 {
+            if deviations[0] > max_deviation_bp {
+                for (q, deviation_bp) in accepted.drain(..).zip(deviations.into_iter()) {
+                    rejected.push(Rejection { name: q.name, reason: RejectReason::Outlier(deviation_bp) });
+                }
+            }
+            break;
+        }
+
+        let mut worst = 0;
+        for i in 1..deviations.len() {
+            if deviations[i] > deviations[worst] {
+                worst = i;
+            }
+        }
+        if deviations[worst] <= max_deviation_bp {
+            break;
+        }
+        let q = accepted.remove(worst);
+        rejected.push(Rejection { name: q.name, reason: RejectReason::Outlier(deviations[worst]) });
+    }
+    return (accepted, rejected);
+}
+
+pub fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = values.len();
+    if n % 2 == 1 {
+        return values[n / 2];
+    }
+    return 0.5 * (values[n / 2 - 1] + values[n / 2]);
+}
+
+
+mod tests {
+    use super::*;
+
+    fn quote(name: &str, bid: f64, ask: f64, weight: f64) -> SourceQuote {
+        return SourceQuote {
+            name: name.to_string(),
+            bid,
+            ask,
+            weight,
+            imbalance: None,
+        };
+    }
+
+    #[test]
+    fn test_aggregate_empty() {
+        assert_eq!(aggregate(&vec![], Aggregation::Widest), None);
+    }
+
+    #[test]
+    fn test_aggregate_widest() {
+        let quotes = vec![quote("a", 99.0, 101.0, 1.0), quote("b", 99.5, 102.0, 1.0)];
+        assert_eq!(aggregate(&quotes, Aggregation::Widest), Some((99.0, 102.0)));
+    }
+
+    #[test]
+    fn test_aggregate_weighted_mid() {
+        let quotes = vec![quote("a", 99.0, 101.0, 3.0), quote("b", 103.0, 105.0, 1.0)];
+        assert_eq!(aggregate(&quotes, Aggregation::WeightedMid), Some((100.0, 102.0)));
+    }
+
+    #[test]
+    fn test_reject_crossed() {
+        let quotes = vec![quote("a", 99.0, 101.0, 1.0), quote("b", 101.0, 100.0, 1.0)];
+        let (accepted, rejected) = reject_outliers(quotes, 50.0);
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(rejected[0].name, "b");
+        assert_eq!(rejected[0].reason, RejectReason::Crossed);
+    }
+
+    #[test]
+    fn test_reject_outliers() {
+        let quotes = vec![
+            quote("a", 99.0, 101.0, 1.0),
+            quote("b", 99.1, 101.1, 1.0),
+            quote("c", 109.0, 111.0, 1.0),
+        ];
+        let (accepted, rejected) = reject_outliers(quotes.clone(), 50.0);
+        assert_eq!(accepted.iter().map(|q| q.name.clone()).collect::<Vec<String>>(), vec!["a", "b"]);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].name, "c");
+
+        // disabled
+        let (accepted, _) = reject_outliers(quotes.clone(), 0.0);
+        assert_eq!(accepted.len(), 3);
+
+        // two sources that disagree, can't tell which one is wrong
+        let (accepted, rejected) = reject_outliers(quotes[1..].to_vec(), 50.0);
+        assert!(accepted.is_empty());
+        assert_eq!(rejected.len(), 2);
+        let (accepted, _) = reject_outliers(quotes[..2].to_vec(), 50.0);
+        assert_eq!(accepted.len(), 2);
+
+        // nothing to compare a single source against
+        let (accepted, _) = reject_outliers(quotes[2..].to_vec(), 50.0);
+        assert_eq!(accepted.len(), 1);
+    }
+
+    #[test]
+    fn test_reject_outlier_moving_the_median() {
+        // d pulls the median of all four mids up to 100.15, only 49.9bp from
+        // it, but it is 54.9bp away from the median of the other three
+        let quotes = vec![
+            quote("a", 99.0, 101.0, 1.0),
+            quote("b", 99.1, 101.1, 1.0),
+            quote("c", 99.2, 101.2, 1.0),
+            quote("d", 99.65, 101.65, 1.0),
+        ];
+        let (accepted, rejected) = reject_outliers(quotes, 50.0);
+        assert_eq!(accepted.iter().map(|q| q.name.clone()).collect::<Vec<String>>(), vec!["a", "b", "c"]);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].name, "d");
+        match rejected[0].reason {
+            RejectReason::Outlier(bp) => assert!((bp - 54.9).abs() < 0.1),
+            _ => panic!("expected an outlier"),
+        }
+    }
+
+    #[test]
+    fn test_aggregate_median() {
+        let quotes = vec![
+            quote("a", 99.0, 101.0, 1.0),
+            quote("b", 98.0, 100.0, 1.0),
+            quote("c", 150.0, 152.0, 1.0),
+        ];
+        assert_eq!(aggregate(&quotes, Aggregation::Median), Some((99.0, 101.0)));
+
+        let quotes = vec![quote("a", 99.0, 101.0, 1.0), quote("b", 101.0, 103.0, 1.0)];
+        assert_eq!(aggregate(&quotes, Aggregation::Median), Some((100.0, 102.0)));
+    }
+
+    struct FixedSource {
+        rp: RefPrice,
+    }
+
+    impl PriceSource for FixedSource {
+        fn name(&self) -> String {
+            return "fixed".to_string();
+        }
+
+        fn latest(&self) -> Option<RefPrice> {
+            return Some(self.rp.clone());
+        }
+    }
+
+    #[test]
+    fn test_fx_scaling() {
+        let mut rp = RefPrice::new();
+        rp.set(0.75, 1.25);
+        let ws = WeightedSource {
+            source: Arc::new(FixedSource { rp: RefPrice::new() }),
+            weight: 1.0,
+            price_scaling: 0.5,
+            fx: Some(Arc::new(FxRate {
+                source: Arc::new(FixedSource { rp }),
+                invert: false,
+            })),
+        };
+        assert_eq!(ws.scaling(Duration::from_secs(10)), 1.0);
+
+        // fx feed that never sent anything, we fall back to the static scaling
+        let ws = WeightedSource {
+            source: Arc::new(FixedSource { rp: RefPrice::new() }),
+            weight: 1.0,
+            price_scaling: 0.5,
+            fx: Some(Arc::new(FxRate {
+                source: Arc::new(FixedSource { rp: RefPrice::new() }),
+                invert: false,
+            })),
+        };
+        assert_eq!(ws.scaling(Duration::from_secs(10)), 0.5);
+
+        let mut rp = RefPrice::new();
+        rp.set(1.5, 2.5);
+        let inverted = FxRate {
+            source: Arc::new(FixedSource { rp }),
+            invert: true,
+        };
+        assert_eq!(inverted.rate(Duration::from_secs(10)), Some(0.5));
+    }
+}
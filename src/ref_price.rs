@@ -1,6 +1,18 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
 pub struct RefPrice {
     bid_price: f64,
     ask_price: f64,
+    // sizes at the best bid / ask, 0 if the venue didn't send them
+    bid_size: f64,
+    ask_size: f64,
+    // depth beyond the top of book, empty unless the feed streams depth
+    book: Book,
+    // exchange timestamp of the quote in millis since unix epoch, 0 if the venue didn't send one
+    exchange_ts: u64,
+    // local time we received the quote, None until the first update
+    received_at: Option<Instant>,
 }
 
 impl RefPrice {
@@ -8,15 +20,276 @@ impl RefPrice {
         return RefPrice {
             bid_price: 0.,
             ask_price: 0.,
+            bid_size: 0.,
+            ask_size: 0.,
+            book: Book::new(),
+            exchange_ts: 0,
+            received_at: None,
         };
     }
 
     pub fn set(&mut self, bid_price: f64, ask_price: f64) {
+        self.set_with_exchange_ts(bid_price, ask_price, 0);
+    }
+
+    pub fn set_with_exchange_ts(&mut self, bid_price: f64, ask_price: f64, exchange_ts: u64) {
+        self.set_top(bid_price, 0.0, ask_price, 0.0, exchange_ts);
+    }
+
+    pub fn set_top(&mut self, bid_price: f64, bid_size: f64, ask_price: f64, ask_size: f64, exchange_ts: u64) {
         self.bid_price = bid_price;
         self.ask_price = ask_price;
+        self.bid_size = bid_size;
+        self.ask_size = ask_size;
+        self.book.clear();
+        self.exchange_ts = exchange_ts;
+        self.received_at = Some(Instant::now());
+    }
+
+    // top of book is taken from the book, returns false (and changes nothing)
+    // if either side is empty
+    pub fn set_book(&mut self, book: Book, exchange_ts: u64) -> bool {
+        let (best_bid, best_ask) = match (book.best_bid(), book.best_ask()) {
+            (Some(b), Some(a)) => (b, a),
+            _ => return false,
+        };
+        self.set_top(best_bid.0, best_bid.1, best_ask.0, best_ask.1, exchange_ts);
+        self.book = book;
+        return true;
+    }
+
+    pub fn clear(&mut self) {
+        *self = RefPrice::new();
     }
 
     pub fn get(&self) -> (f64, f64) {
         return (self.bid_price, self.ask_price);
     }
+
+    pub fn sizes(&self) -> (f64, f64) {
+        return (self.bid_size, self.ask_size);
+    }
+
+    pub fn book(&self) -> &Book {
+        return &self.book;
+    }
+
+    // Volume weighted price we'd get trading `notional` (in quote currency)
+    // against one side of the book. If the book is too thin the remainder is
+    // assumed to fill at the deepest level we know about. None without depth.
+    pub fn vwap_for_notional(&self, side: BookSide, notional: f64) -> Option<f64> {
+        let levels = match side {
+            BookSide::Bid => self.book.bids(),
+            BookSide::Ask => self.book.asks(),
+        };
+        if levels.is_empty() || notional <= 0.0 {
+            return None;
+        }
+
+        let mut remaining = notional;
+        let mut qty = 0.0;
+        for (price, size) in levels.iter() {
+            let level_notional = price * size;
+            if level_notional >= remaining {
+                qty += remaining / price;
+                remaining = 0.0;
+                break;
+            }
+            qty += size;
+            remaining -= level_notional;
+        }
+        if remaining > 0.0 {
+            qty += remaining / levels.last().unwrap().0;
+        }
+        return Some(notional / qty);
+    }
+
+    // for prices we copy from somewhere else rather than receive ourselves
+    pub fn set_received_at(&mut self, received_at: Instant) {
+        self.received_at = Some(received_at);
+    }
+
+    pub fn exchange_ts(&self) -> u64 {
+        return self.exchange_ts;
+    }
+
+    // time since we last received a quote, None if we never did
+    pub fn age(&self) -> Option<Duration> {
+        return self.received_at.map(|t| t.elapsed());
+    }
+
+    // a price we never received is stale too
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        return match self.age() {
+            Some(age) => age > max_age,
+            None => true,
+        };
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BookSide {
+    Bid,
+    Ask,
+}
+
+// Local copy of a venue's order book built from snapshots and deltas.
+// Levels are (price, size), best first on both sides.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Book {
+    bids: Vec<(f64, f64)>,
+    asks: Vec<(f64, f64)>,
+}
+
+impl Book {
+    pub fn new() -> Book {
+        return Book {
+            bids: vec![],
+            asks: vec![],
+        };
+    }
+
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    // a size of zero removes the level
+    pub fn set_level(&mut self, side: BookSide, price: f64, size: f64) {
+        let levels = match side {
+            BookSide::Bid => &mut self.bids,
+            BookSide::Ask => &mut self.asks,
+        };
+
+        if let Some(idx) = levels.iter().position(|l| l.0 == price) {
+            if size <= 0.0 {
+                levels.remove(idx);
+            } else {
+                levels[idx].1 = size;
+            }
+            return;
+        }
+
+        if size <= 0.0 {
+            return;
+        }
+
+        let idx = match side {
+            BookSide::Bid => levels.iter().position(|l| l.0 < price),
+            BookSide::Ask => levels.iter().position(|l| l.0 > price),
+        }
+        .unwrap_or(levels.len());
+        levels.insert(idx, (price, size));
+    }
+
+    // keep only the best `depth` levels per side, for venues that expect the
+    // client to drop levels that fall out of the subscribed depth
+    pub fn truncate(&mut self, depth: usize) {
+        self.bids.truncate(depth);
+        self.asks.truncate(depth);
+    }
+
+    // copy of the best `depth` levels per side, without copying the rest
+    pub fn top(&self, depth: usize) -> Book {
+        return Book {
+            bids: self.bids.iter().take(depth).cloned().collect(),
+            asks: self.asks.iter().take(depth).cloned().collect(),
+        };
+    }
+
+    pub fn best_bid(&self) -> Option<(f64, f64)> {
+        return self.bids.first().cloned();
+    }
+
+    pub fn best_ask(&self) -> Option<(f64, f64)> {
+        return self.asks.first().cloned();
+    }
+
+    pub fn bids(&self) -> &Vec<(f64, f64)> {
+        return &self.bids;
+    }
+
+    pub fn asks(&self) -> &Vec<(f64, f64)> {
+        return &self.asks;
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ref_price_staleness() {
+        let mut rp = RefPrice::new();
+        assert!(rp.is_stale(Duration::from_secs(10)));
+        assert_eq!(rp.age(), None);
+
+        rp.set_with_exchange_ts(99.0, 101.0, 1_700_000_000_000);
+        assert!(!rp.is_stale(Duration::from_secs(10)));
+        assert_eq!(rp.get(), (99.0, 101.0));
+        assert_eq!(rp.exchange_ts(), 1_700_000_000_000);
+
+        rp.clear();
+        assert!(rp.is_stale(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_ref_price_set_book() {
+        let mut rp = RefPrice::new();
+        let mut book = Book::new();
+        book.set_level(BookSide::Bid, 99.0, 1.0);
+        assert!(!rp.set_book(book.clone(), 1));
+        assert_eq!(rp.get(), (0.0, 0.0));
+
+        book.set_level(BookSide::Ask, 101.0, 2.0);
+        book.set_level(BookSide::Ask, 102.0, 3.0);
+        assert!(rp.set_book(book, 1));
+        assert_eq!(rp.get(), (99.0, 101.0));
+        assert_eq!(rp.sizes(), (1.0, 2.0));
+        assert_eq!(rp.book().asks().len(), 2);
+    }
+
+    #[test]
+    fn test_vwap_for_notional() {
+        let mut rp = RefPrice::new();
+        assert_eq!(rp.vwap_for_notional(BookSide::Ask, 100.0), None);
+
+        let mut book = Book::new();
+        book.set_level(BookSide::Bid, 100.0, 1.0);
+        book.set_level(BookSide::Bid, 90.0, 10.0);
+        book.set_level(BookSide::Ask, 100.0, 1.0);
+        book.set_level(BookSide::Ask, 200.0, 1.0);
+        rp.set_book(book, 0);
+
+        // fits in the top level
+        assert_eq!(rp.vwap_for_notional(BookSide::Bid, 50.0), Some(100.0));
+        // 100 notional at 100 (1 unit) and 90 notional at 90 (1 unit)
+        assert_eq!(rp.vwap_for_notional(BookSide::Bid, 190.0), Some(95.0));
+        // book too thin: 300 across both levels, remaining 100 at the last level 200
+        assert_eq!(rp.vwap_for_notional(BookSide::Ask, 400.0), Some(400.0 / 2.5));
+    }
+
+    #[test]
+    fn test_book_set_level() {
+        let mut book = Book::new();
+        book.set_level(BookSide::Bid, 99.0, 1.0);
+        book.set_level(BookSide::Bid, 100.0, 2.0);
+        book.set_level(BookSide::Bid, 98.0, 3.0);
+        book.set_level(BookSide::Ask, 102.0, 1.0);
+        book.set_level(BookSide::Ask, 101.0, 4.0);
+        assert_eq!(book.bids(), &vec![(100.0, 2.0), (99.0, 1.0), (98.0, 3.0)]);
+        assert_eq!(book.best_ask(), Some((101.0, 4.0)));
+
+        // update in place and remove
+        book.set_level(BookSide::Bid, 99.0, 5.0);
+        book.set_level(BookSide::Bid, 100.0, 0.0);
+        book.set_level(BookSide::Ask, 105.0, 0.0);
+        assert_eq!(book.bids(), &vec![(99.0, 5.0), (98.0, 3.0)]);
+        assert_eq!(book.asks(), &vec![(101.0, 4.0), (102.0, 1.0)]);
+
+        let top = book.top(1);
+        assert_eq!(top.bids(), &vec![(99.0, 5.0)]);
+        assert_eq!(top.asks(), &vec![(101.0, 4.0)]);
+    }
 }
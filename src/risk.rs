@@ -0,0 +1,242 @@
+use log::{error, info, warn};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// All in asset units, 0 disables a limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RiskLimits {
+    pub max_session_loss: f64,
+    pub max_drawdown: f64,
+    pub max_position_notional: f64,
+    pub max_order_notional: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RiskBreach {
+    SessionLoss { loss: f64, limit: f64 },
+    Drawdown { drawdown: f64, limit: f64 },
+    PositionNotional { notional: f64, limit: f64 },
+    OrderNotional { notional: f64, limit: f64 },
+}
+
+impl RiskBreach {
+    pub fn limit_name(&self) -> &'static str {
+        match self {
+            RiskBreach::SessionLoss { .. } => "max_session_loss",
+            RiskBreach::Drawdown { .. } => "max_drawdown",
+            RiskBreach::PositionNotional { .. } => "max_position_notional",
+            RiskBreach::OrderNotional { .. } => "max_order_notional",
+        }
+    }
+}
+
+impl fmt::Display for RiskBreach {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RiskBreach::SessionLoss { loss, limit } => write!(f, "session loss {:.4} over {}", loss, limit),
+            RiskBreach::Drawdown { drawdown, limit } => write!(f, "drawdown {:.4} over {}", drawdown, limit),
+            RiskBreach::PositionNotional { notional, limit } => {
+                write!(f, "position notional {:.4} over {}", notional, limit)
+            }
+            RiskBreach::OrderNotional { notional, limit } => {
+                write!(f, "outstanding order notional {:.4} over {}", notional, limit)
+            }
+        }
+    }
+}
+
+// What the risk manager looks at every cycle, in asset units.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RiskInputs {
+    // we have the positions snapshot, and so any position we started with
+    pub positions_loaded: bool,
+    // session P&L including unrealised
+    pub equity: f64,
+    pub position_notional: f64,
+    pub order_notional: f64,
+}
+
+// Trips on the first limit breached and stays halted until an operator
+// resets it. With a halt file the trip is written there and deleting the file
+// is the reset; the file also keeps us halted across restarts. Without one
+// only a restart resets.
+pub struct RiskManager {
+    limits: RiskLimits,
+    halt_file: Option<String>,
+    // equity when the session started (or the last reset) and its peak since
+    start_equity: Option<f64>,
+    peak_equity: f64,
+    halted: Option<String>,
+}
+
+impl RiskManager {
+    pub fn new(limits: RiskLimits, halt_file: Option<String>) -> RiskManager {
+        let mut halted = None;
+        if let Some(path) = halt_file.as_ref() {
+            if Path::new(path).exists() {
+                let reason = fs::read_to_string(path).unwrap_or_default();
+                error!("halt file {} exists, starting halted: {}", path, reason.trim());
+                halted = Some(reason.trim().to_string());
+            }
+        }
+        return RiskManager {
+            limits,
+            halt_file,
+            start_equity: None,
+            peak_equity: 0.0,
+            halted,
+        };
+    }
+
+    // Some(reason) while halted. Notices the operator deleting the halt file,
+    // the loss and drawdown then count from the equity at the next check.
+    pub fn halted(&mut self) -> Option<String> {
+        if self.halted.is_some() {
+            if let Some(path) = self.halt_file.as_ref() {
+                if !Path::new(path).exists() {
+                    warn!("halt file {} removed, resuming", path);
+                    self.halted = None;
+                    self.start_equity = None;
+                }
+            }
+        }
+        return self.halted.clone();
+    }
+
+    // Nothing is checked before our position is loaded: the session would
+    // start at an equity without the unrealised P&L of a position we already
+    // had, and loading it would then look like a loss.
+    pub fn check(&mut self, i: &RiskInputs) -> Option<RiskBreach> {
+        if !i.positions_loaded {
+            return None;
+        }
+        let start = match self.start_equity {
+            Some(s) => s,
+            None => {
+                self.start_equity = Some(i.equity);
+                self.peak_equity = i.equity;
+                i.equity
+            }
+        };
+        self.peak_equity = self.peak_equity.max(i.equity);
+
+        let l = &self.limits;
+        let loss = start - i.equity;
+        if l.max_session_loss > 0.0 && loss > l.max_session_loss {
+            return Some(RiskBreach::SessionLoss { loss, limit: l.max_session_loss });
+        }
+        let drawdown = self.peak_equity - i.equity;
+        if l.max_drawdown > 0.0 && drawdown > l.max_drawdown {
+            return Some(RiskBreach::Drawdown { drawdown, limit: l.max_drawdown });
+        }
+        if l.max_position_notional > 0.0 && i.position_notional > l.max_position_notional {
+            return Some(RiskBreach::PositionNotional {
+                notional: i.position_notional,
+                limit: l.max_position_notional,
+            });
+        }
+        if l.max_order_notional > 0.0 && i.order_notional > l.max_order_notional {
+            return Some(RiskBreach::OrderNotional {
+                notional: i.order_notional,
+                limit: l.max_order_notional,
+            });
+        }
+        return None;
+    }
+
+    // latches the halt and logs the trip as its own event
+    pub fn trip(&mut self, breach: &RiskBreach) {
+        let reason = format!("{}: {}", breach.limit_name(), breach);
+        error!("RISK TRIP {}", reason);
+        if let Some(path) = self.halt_file.as_ref() {
+            match fs::write(path, format!("{}\n", reason)) {
+                Ok(()) => info!("wrote halt file {}, delete it to resume", path),
+                Err(e) => error!("could not write halt file {}: {}", path, e),
+            }
+        }
+        self.halted = Some(reason);
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    fn limits() -> RiskLimits {
+        return RiskLimits {
+            max_session_loss: 100.0,
+            max_drawdown: 50.0,
+            max_position_notional: 1000.0,
+            max_order_notional: 2000.0,
+        };
+    }
+
+    fn inputs(equity: f64) -> RiskInputs {
+        return RiskInputs {
+            positions_loaded: true,
+            equity,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut r = RiskManager::new(limits(), None);
+        // the session starts at whatever the equity is
+        assert_eq!(r.check(&inputs(20.0)), None);
+        assert_eq!(r.check(&inputs(60.0)), None);
+        assert_eq!(
+            r.check(&inputs(5.0)),
+            Some(RiskBreach::Drawdown { drawdown: 55.0, limit: 50.0 })
+        );
+        assert_eq!(r.check(&inputs(-81.0)).unwrap().limit_name(), "max_session_loss");
+
+        let mut r = RiskManager::new(limits(), None);
+        let mut big = inputs(20.0);
+        big.position_notional = 1001.0;
+        assert_eq!(r.check(&big).unwrap().limit_name(), "max_position_notional");
+        big.position_notional = 0.0;
+        big.order_notional = 2001.0;
+        assert_eq!(r.check(&big).unwrap().limit_name(), "max_order_notional");
+
+        assert_eq!(RiskManager::new(RiskLimits::default(), None).check(&inputs(-1e9)), None);
+    }
+
+    #[test]
+    fn test_waits_for_the_position() {
+        let mut r = RiskManager::new(limits(), None);
+        let mut not_loaded = inputs(0.0);
+        not_loaded.positions_loaded = false;
+        not_loaded.position_notional = 5000.0;
+        assert_eq!(r.check(&not_loaded), None);
+
+        // a position we had before we started, 500 under water
+        assert_eq!(r.check(&inputs(-500.0)), None);
+        assert_eq!(r.check(&inputs(-550.0)), None);
+        assert_eq!(r.check(&inputs(-601.0)).unwrap().limit_name(), "max_session_loss");
+    }
+
+    #[test]
+    fn test_halt_latches_until_file_removed() {
+        let path = std::env::temp_dir().join(format!("risk_halt_test_{}", std::process::id()));
+        let path_s = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut r = RiskManager::new(limits(), Some(path_s.clone()));
+        assert_eq!(r.halted(), None);
+        r.check(&inputs(0.0));
+        let breach = r.check(&inputs(-101.0)).unwrap();
+        r.trip(&breach);
+        assert!(r.halted().unwrap().starts_with("max_session_loss"));
+        assert!(r.halted().is_some());
+
+        // still halted after a restart
+        assert!(RiskManager::new(limits(), Some(path_s.clone())).halted().is_some());
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(r.halted(), None);
+        // the loss counts from the reset
+        assert_eq!(r.check(&inputs(-101.0)), None);
+    }
+}
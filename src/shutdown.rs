@@ -0,0 +1,104 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::timeout;
+use vega_crypto::{Credentials, Transact};
+
+use crate::flatten;
+use crate::liquidity_vega;
+use crate::node_pool::NodePool;
+use crate::strategy2;
+use crate::vega_store2::VegaStore;
+use crate::Config;
+
+// What to do with our liquidity commitment on the way out.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownLiquidity {
+    // leave it as it is
+    Keep,
+    // amend it to shutdown_bond_amount
+    Amend,
+    Cancel,
+}
+
+impl Default for ShutdownLiquidity {
+    fn default() -> Self {
+        ShutdownLiquidity::Keep
+    }
+}
+
+// resolves on SIGINT or SIGTERM
+pub async fn wait_for_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("could not install the SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+        _ = term.recv() => info!("received SIGTERM"),
+    }
+}
+
+// Cancels all our orders, then depending on the config amends or cancels the
+// liquidity commitment and flattens the position. Each step gets
+// shutdown_timeout to finish, except the flatten which works through slices
+// and gets flatten_timeout instead; a step that times out is logged and skipped.
+pub async fn shutdown(w1: Transact, config: Config, store: Arc<Mutex<VegaStore>>, pool: Arc<Mutex<NodePool>>) {
+    let step_timeout = Duration::from_secs_f64(config.shutdown_timeout);
+
+    // the strategy may have moved to another datanode since we started
+    let url = pool.lock().unwrap().current_url();
+    let mut w1 = match timeout(
+        step_timeout,
+        Transact::new(Credentials::Mnemonic(&config.wallet_mnemonic_1, 1), url.clone()),
+    )
+    .await
+    {
+        Ok(Ok(w)) => w,
+        Ok(Err(e)) => {
+            warn!("could not connect to {} for the shutdown, using the startup node: {:?}", url, e);
+            w1
+        }
+        Err(_) => {
+            warn!("timed out connecting to {} for the shutdown, using the startup node", url);
+            w1
+        }
+    };
+
+    info!("cancelling all our orders");
+    if timeout(step_timeout, strategy2::cancel_all(&mut w1, &config)).await.is_err() {
+        error!("timed out cancelling our orders");
+    }
+
+    match config.shutdown_liquidity {
+        ShutdownLiquidity::Keep => {}
+        ShutdownLiquidity::Amend => {
+            let mut c = config.clone();
+            c.bond_amount = config.shutdown_bond_amount;
+            info!("amending our liquidity commitment to {}", c.bond_amount);
+            let amend = liquidity_vega::update_liquidity_commitment(w1.clone(), c, store.clone());
+            if timeout(step_timeout, amend).await.is_err() {
+                error!("timed out amending our liquidity commitment");
+            }
+        }
+        ShutdownLiquidity::Cancel => {
+            info!("cancelling our liquidity commitment");
+            let cancel = liquidity_vega::cancel_liquidity_commitment(w1.clone(), config.clone(), store.clone());
+            if timeout(step_timeout, cancel).await.is_err() {
+                error!("timed out cancelling our liquidity commitment");
+            }
+        }
+    }
+
+    // flatten gives up by itself after flatten_timeout, the timeout here only
+    // catches a send that hangs; a pegged slice left behind expires after
+    // flatten_interval
+    if config.shutdown_flatten {
+        info!("flattening our position");
+        let flatten_timeout = Duration::from_secs_f64(config.flatten_timeout);
+        if timeout(flatten_timeout, flatten::flatten(&mut w1, &config, &store)).await.is_err() {
+            error!("timed out flattening our position");
+        }
+    }
+    info!("shutdown done");
+}
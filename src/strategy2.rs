@@ -1,5 +1,5 @@
 use futures_util::lock::MutexGuard as FuturesUtilsMutexGuard;
-use log::info;
+use log::{error, info, warn};
 use num_traits::ToPrimitive;
 use vega_protobufs::vega::events::v1::ExpiredOrders;
 use core::num;
@@ -13,7 +13,7 @@ use tokio::time;
 use rand::prelude::*;
 
 
-use vega_crypto::Transact;
+use vega_crypto::{Credentials, Transact};
 use vega_protobufs::vega::{
     commands::v1::{
         input_data::Command, 
@@ -31,11 +31,24 @@ use vega_protobufs::vega::{
 use vega_protobufs::vega::{Asset, Position};
 use vega_protobufs::vega::MarketData;
 
-use crate::{vega_store2::VegaStore};
+use crate::{vega_store2::{VegaStore, VegaStream}};
 use crate::{Config, vega_store2};
 use crate::opt_offsets;
-use crate::estimate_params::{self, estimate_lambda2, estimate_kappa};
+use crate::liquidity_vega::bond_is_funded;
+use crate::estimate_params::{self, estimate_lambda2, estimate_kappa, estimate_kappa_from_fills};
+use crate::fills::fill_stats;
+use crate::pnl::PnlEngine;
+use crate::risk::{RiskInputs, RiskLimits, RiskManager};
+use crate::flatten;
 use crate::ref_price::RefPrice;
+use crate::price_source::{aggregate, reject_outliers, source_bid_ask, RejectReason, SourceQuote, WeightedSource};
+use crate::metrics::Metrics;
+use crate::node_pool::NodePool;
+use crate::order_diff::{self, ladder_reference};
+use crate::fair_value::{book_imbalance, combined_imbalance, fair_value, size_imbalance, FairValueMode};
+
+// trades older than this don't count towards the lambda / kappa estimates
+pub const ESTIMATION_WINDOW: Duration = Duration::from_secs(30 * 60);
 
 #[derive(Debug, PartialEq)]
 pub enum PositionSituation {
@@ -48,10 +61,11 @@ pub async fn start(
     mut w1: Transact,
     config: Config,
     store: Arc<Mutex<VegaStore>>,
-    binance_rp: Arc<Mutex<RefPrice>>,
-    bybit_rp: Arc<Mutex<RefPrice>>,
+    pool: Arc<Mutex<NodePool>>,
+    sources: Vec<WeightedSource>,
     old_rp: Arc<Mutex<RefPrice>>,
     skip_counter: Arc<Mutex<u64>>,
+    metrics: Arc<Mutex<Metrics>>,
 ) {
     // just loop forever, waiting for user interupt
     info!(
@@ -83,12 +97,38 @@ pub async fn start(
         info!("drurun mode, at this stage would submit a close orders transaction"); 
     }
 
+    let mut pnl = PnlEngine::new(d.price_factor, d.position_factor, d.asset_factor);
+    let mut risk = RiskManager::new(
+        RiskLimits {
+            max_session_loss: config.max_session_loss,
+            max_drawdown: config.max_drawdown,
+            max_position_notional: config.max_position_notional,
+            max_order_notional: config.max_order_notional,
+        },
+        config.risk_halt_file.clone(),
+    );
     let mut interval = time::interval(Duration::from_secs_f64(config.submission_rate));
+    let mut node_generation = pool.lock().unwrap().generation();
 
     loop {
         tokio::select! {
             _ = interval.tick() => {
                 interval.reset();
+                // the node monitor moved us to another datanode, send from there too
+                let (url, generation) = {
+                    let p = pool.lock().unwrap();
+                    (p.current_url(), p.generation())
+                };
+                if generation != node_generation {
+                    match Transact::new(Credentials::Mnemonic(&config.wallet_mnemonic_1, 1), url.clone()).await {
+                        Ok(w) => {
+                            info!("transactions now sent through {}", url);
+                            w1 = w;
+                            node_generation = generation;
+                        }
+                        Err(e) => error!("could not connect to {} for transactions, keeping the old node: {:?}", url, e),
+                    }
+                }
                 // let extra_sleep = rand::random::<u64>() % submission_rate;
                 // info!("adding extra sleep of {} seconds before starting", extra_sleep);
                 // // add some extra time here jsut to look a little bit less scripted
@@ -96,10 +136,12 @@ pub async fn start(
                 run_strategy(&mut w1, 
                     &config, 
                     store.clone(), 
-                    binance_rp.clone(),
-                    bybit_rp.clone(),
+                    &sources,
                     old_rp.clone(),
                     skip_counter.clone(),
+                    metrics.clone(),
+                    &mut pnl,
+                    &mut risk,
                 ).await;
             }
         }
@@ -110,10 +152,12 @@ async fn run_strategy(
     w1: &mut Transact,
     c: &Config,
     store: Arc<Mutex<VegaStore>>,
-    binance_rp: Arc<Mutex<RefPrice>>,
-    bybit_rp: Arc<Mutex<RefPrice>>,
+    sources: &Vec<WeightedSource>,
     old_rp: Arc<Mutex<RefPrice>>,
     skip_counter: Arc<Mutex<u64>>,
+    metrics: Arc<Mutex<Metrics>>,
+    pnl: &mut PnlEngine,
+    risk: &mut RiskManager,
 ) {
     if c.q_lower >= c.q_upper {
         panic!("we need q_lower < q_upper");
@@ -143,82 +187,124 @@ async fn run_strategy(
 
     let d = Decimals::new(&mkt, &asset);
 
-    let mut binance_best_bid = 0 as u64;
-    let mut binance_best_ask = 0 as u64; 
+    // read before the P&L update so that a position that loaded is in the P&L
+    let positions_loaded = store.lock().unwrap().positions_loaded();
+    update_pnl(pnl, &store, &*w1.public_key(), &asset.id, c.max_pnl_drift, &d);
 
-    if c.use_binance_bidask {
-        let (binance_best_bid_f, binance_best_ask_f) = binance_rp.lock().unwrap().get();
-        if binance_best_ask_f <= 0.0 || binance_best_ask_f <= 0.0 {
-            info!("At least one Binance price is NOT +ve! Either error or prices not updated yet.");
-            return;
+    // a halt holds whatever the streams and prices do
+    if let Some(reason) = risk.halted() {
+        info!("halted by the risk manager ({}), not quoting.", reason);
+        pull_quotes(w1, c, old_rp).await;
+        return;
+    }
+    let inputs = risk_inputs(pnl, &store, positions_loaded, &d);
+    if let Some(breach) = risk.check(&inputs) {
+        risk.trip(&breach);
+        metrics.lock().unwrap().incr(&format!("risk_trip_{}", breach.limit_name()));
+        // pull_quotes would skip this if we had already pulled them
+        cancel_all(w1, c).await;
+        old_rp.lock().unwrap().clear();
+        if c.risk_flatten_on_trip {
+            flatten::flatten(w1, c, &store).await;
         }
-        binance_best_bid = (c.binance_price_scaling * binance_best_bid_f * d.price_factor) as u64;
-        binance_best_ask = (c.binance_price_scaling * binance_best_ask_f * d.price_factor) as u64;
-        info!(
-            "new Binance reference prices: bestBid({}), bestAsk({}))", binance_best_bid, binance_best_ask);
+        return;
     }
-    
-    let mut bybit_best_bid = 0 as u64;
-    let mut bybit_best_ask = 0 as u64;
-    if c.use_bybit_bidask {
-        let (bybit_best_bid_f, bybit_best_ask_f) = bybit_rp.lock().unwrap().get();
 
-        if bybit_best_bid_f <= 0.0 || bybit_best_ask_f <= 0.0 {
-            info!("At least one Bybit price is NOT +ve! Either error or prices not updated yet.");
-            return;
+    // don't trade on a frozen view of our position or of the vega book
+    let unhealthy = store
+        .lock()
+        .unwrap()
+        .unhealthy_streams(Duration::from_secs_f64(c.max_vega_stream_age), c.incremental_orders);
+    if !unhealthy.is_empty() {
+        info!("vega streams not healthy ({}), pulling our quotes.", unhealthy.join(", "));
+        pull_quotes(w1, c, old_rp).await;
+        return;
+    }
+
+    let max_ref_price_age = Duration::from_secs_f64(c.max_ref_price_age);
+    let ref_vwap_notional = if c.ref_vwap_notional > 0.0 {
+        c.ref_vwap_notional
+    } else {
+        c.volume_of_notional as f64
+    };
+    let mut quotes: Vec<SourceQuote> = vec![];
+    for s in sources.iter() {
+        let name = s.source.name();
+        let rp = match s.source.latest() {
+            Some(rp) => rp,
+            None => {
+                info!("No {} reference price yet, not using it", name);
+                continue;
+            }
+        };
+        if rp.is_stale(max_ref_price_age) {
+            info!("{} reference price is stale (age {:?}), not using it", name, rp.age());
+            continue;
         }
 
-        bybit_best_bid = (bybit_best_bid_f * d.price_factor) as u64;
-        bybit_best_ask = (bybit_best_ask_f * d.price_factor) as u64;
-        info!(
-            "new Bybit reference prices: bestBid({}), bestAsk({}))", bybit_best_bid, bybit_best_ask);
+        let (bid, ask) = source_bid_ask(&rp, c.ref_price_mode, ref_vwap_notional);
+        let scaling = s.scaling(max_ref_price_age);
+        let bid = scaling * bid;
+        let ask = scaling * ask;
+        let imbalance = book_imbalance(&rp, c.fair_value_mode, c.imbalance_depth);
+        info!("new {} reference prices: bestBid({}), bestAsk({}), imbalance: {:?}", name, bid, ask, imbalance);
+        quotes.push(SourceQuote { name, bid, ask, weight: s.weight, imbalance });
     }
 
-    let md = store.lock().unwrap().get_market_data();
-    
-    let vega_best_bid = BigUint::parse_bytes(md.best_bid_price.as_bytes(), 10).unwrap().to_u64().unwrap_or(0);
-    let vega_best_ask = BigUint::parse_bytes(md.best_offer_price.as_bytes(), 10).unwrap().to_u64().unwrap_or(0);
-    if c.use_vega_bidask && (vega_best_bid == 0 || vega_best_ask == 0) {
-        info!("At least one vega price is NOT +ve! Either error or prices not updated yet.");
-        return
+    let (quotes, rejected) = reject_outliers(quotes, c.max_source_deviation_bp);
+    if !rejected.is_empty() {
+        let mut m = metrics.lock().unwrap();
+        for r in rejected.iter() {
+            info!("rejected {} reference price: {:?}", r.name, r.reason);
+            match r.reason {
+                RejectReason::Crossed => m.incr(&format!("ref_rejected_crossed_{}", r.name)),
+                RejectReason::Outlier(_) => m.incr(&format!("ref_rejected_outlier_{}", r.name)),
+            }
+        }
+        m.log();
     }
-    info!(
-        "new Vega reference prices: bestBid({}), bestAsk({})", vega_best_bid, vega_best_ask);
 
-    let mut used_ask: u64;
-    let mut used_bid: u64;
-    
-    if c.use_vega_bidask && c.use_binance_bidask && c.use_bybit_bidask{
-        // best ask we take the bigger one
-        let used_ask_intermediate = std::cmp::max(binance_best_ask, vega_best_ask);
-        used_ask = std::cmp::max(used_ask_intermediate, bybit_best_ask);
-        
-        // best bid we take the smaller one
-        let used_bid_intermediate = std::cmp::min(binance_best_bid, vega_best_bid);    
-        used_bid = std::cmp::min(used_bid_intermediate, bybit_best_bid);    
-    }
-    else if c.use_vega_bidask && c.use_binance_bidask {
-        // best ask we take the bigger one
-        used_ask = std::cmp::max(binance_best_ask, vega_best_ask);
-        // best bid we take the smaller one
-        used_bid = std::cmp::min(binance_best_bid, vega_best_bid);    
-    }
-    else if c.use_vega_bidask && !c.use_binance_bidask && !c.use_bybit_bidask {
-        used_ask = vega_best_ask;
-        used_bid = vega_best_bid;
-    }
-    else if !c.use_vega_bidask && c.use_binance_bidask && !c.use_bybit_bidask {
-        used_ask = binance_best_ask; 
-        used_bid = binance_best_bid; 
-    }
-    else if !c.use_vega_bidask && !c.use_binance_bidask && c.use_bybit_bidask {
-        used_ask = bybit_best_ask; 
-        used_bid = bybit_best_bid; 
-    }
-    else {
-        info!("We must use one of Binance OR Vega OR max on ask side, min on bid side.");
-        return;
+    let (agg_bid, agg_ask) = match aggregate(&quotes, c.ref_aggregation) {
+        Some(p) => p,
+        None => {
+            info!("No fresh reference price source left, pulling our quotes.");
+            pull_quotes(w1, c, old_rp).await;
+            return;
+        }
+    };
+    // centre the reference bid / ask on the fair value instead of the plain mid.
+    // Vega's own top of book always counts, whether or not it is a reference
+    // source; its quote is left out so it doesn't count twice.
+    let mut imbalances: Vec<(f64, f64)> = quotes
+        .iter()
+        .filter(|q| q.name != "vega")
+        .filter_map(|q| q.imbalance.map(|i| (q.weight, i)))
+        .collect();
+    if c.fair_value_mode != FairValueMode::Mid {
+        let md = store.lock().unwrap().get_market_data();
+        if let Some(i) = size_imbalance(md.best_bid_volume as f64, md.best_offer_volume as f64) {
+            imbalances.push((c.vega_imbalance_weight, i));
+        }
     }
+    let (agg_bid, agg_ask) = match combined_imbalance(&imbalances) {
+        Some(imbalance) if c.fair_value_mode != FairValueMode::Mid => {
+            let fair = fair_value(agg_bid, agg_ask, imbalance, c.imbalance_weight);
+            let shift = fair - 0.5 * (agg_bid + agg_ask);
+            info!("fair value {:.5} from imbalance {:.3}, shifting reference prices by {:.5}", fair, imbalance, shift);
+            (agg_bid + shift, agg_ask + shift)
+        }
+        _ => (agg_bid, agg_ask),
+    };
+    let mut used_bid = (agg_bid * d.price_factor) as u64;
+    let mut used_ask = (agg_ask * d.price_factor) as u64;
+
+    let md = store.lock().unwrap().get_market_data();
+    
+    // an empty vega book is fine as long as another source gave us a price
+    let vega_best_bid = BigUint::parse_bytes(md.best_bid_price.as_bytes(), 10).and_then(|p| p.to_u64()).unwrap_or(0);
+    let vega_best_ask = BigUint::parse_bytes(md.best_offer_price.as_bytes(), 10).and_then(|p| p.to_u64()).unwrap_or(0);
+    info!(
+        "Vega best prices: bestBid({}), bestAsk({})", vega_best_bid, vega_best_ask);
 
     if used_ask <= 0 || used_bid <= 0 {
         info!("reference price are not up to date yet");
@@ -260,9 +346,27 @@ async fn run_strategy(
 
     
 
+    // the config lambda and kappa stand in while the streams they are
+    // estimated from are down
+    let (trades_up, fills_up, depth_up, orders_up) = {
+        let s = store.lock().unwrap();
+        (
+            s.stream_up(VegaStream::Trades),
+            s.stream_up(VegaStream::Fills),
+            s.stream_up(VegaStream::Depth),
+            s.stream_up(VegaStream::Orders),
+        )
+    };
+    if !trades_up {
+        info!("trades stream is down, using the config lambda and kappa");
+    }
+    if c.kappa_from_fills && !fills_up {
+        info!("fills stream is down, estimating kappa from the market trades");
+    }
+
     let mut lambd = c.lambd;
     let mut kappa = c.kappa;
-    if !store.lock().unwrap().get_trades().is_empty() {
+    if trades_up && !store.lock().unwrap().get_trades().is_empty() {
         //let last_trade_timestamp = store.lock().unwrap().get_trades().last().unwrap().timestamp;
         //info!("Trying trade store stuff...{}", last_trade_timestamp);
         
@@ -275,7 +379,7 @@ async fn run_strategy(
         // Get the duration in nanoseconds
         let current_t = duration_since_epoch.as_nanos() as u64;
 
-        let estimation_interval = Duration::from_secs(30*60).as_nanos() as u64;
+        let estimation_interval = ESTIMATION_WINDOW.as_nanos() as u64;
 
         store.lock().unwrap().prune_trades_older_than(current_t - estimation_interval);
 
@@ -285,6 +389,40 @@ async fn run_strategy(
         info!("Lambda estimate: {}, Kappa estimate: {}", lambd, kappa);
     }
 
+    let fills = store.lock().unwrap().get_fills();
+    if !fills.is_empty() {
+        let current_t = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_nanos() as u64;
+        let estimation_interval = ESTIMATION_WINDOW.as_nanos() as u64;
+        let stats = fill_stats(&fills, current_t, estimation_interval);
+        info!(
+            "fills in the last {}s: {} ({} maker), {:.3} per minute, bought {}, sold {}, fees {}",
+            ESTIMATION_WINDOW.as_secs(),
+            stats.count,
+            stats.maker_count,
+            stats.fills_per_minute,
+            stats.buy_volume as f64 / d.position_factor,
+            stats.sell_volume as f64 / d.position_factor,
+            stats.fees / d.asset_factor
+        );
+        if c.kappa_from_fills && fills_up {
+            match estimate_kappa_from_fills(
+                c.kappa,
+                c.kappa_weight,
+                current_t,
+                estimation_interval,
+                &fills,
+                d.price_factor,
+                c.min_kappa_fills,
+            ) {
+                Some(k) => {
+                    kappa = k;
+                    info!("Kappa estimate from our fills: {}", kappa);
+                }
+                None => info!("fewer than {} maker fills to estimate kappa from, using {}", c.min_kappa_fills, kappa),
+            }
+        }
+    }
+
     let (buy_deltas, sell_deltas) = opt_offsets::calculate_offsets(c.q_lower, c.q_upper, kappa, lambd, c.phi, d.position_factor);
     let (mut ask_offset, submit_asks, mut bid_offset, submit_bids) =
             opt_offsets::offsets_from_position(buy_deltas, sell_deltas, c.q_lower, c.q_upper, position_size);
@@ -295,6 +433,32 @@ async fn run_strategy(
     let used_bid = used_bid as f64 / d.price_factor;
     let used_ask = used_ask as f64 / d.price_factor;
     let used_mid_price = ((used_ask + used_bid)/2.0) as f64;
+
+    // don't be the first in line: keep our best bid / ask behind at least
+    // min_volume_ahead of other people's orders on the vega book. Needs the
+    // book and our orders to tell the two apart, skipped while either is down.
+    if c.min_volume_ahead > 0.0 && !(depth_up && orders_up) {
+        info!("depth or orders stream is down, not checking min_volume_ahead");
+    } else if c.min_volume_ahead > 0.0 {
+        let book = store.lock().unwrap().get_book_excluding_own();
+        let volume = (c.min_volume_ahead * d.position_factor).ceil() as u64;
+        let (bid_ref, ask_ref) = if c.use_mid { (used_mid_price, used_mid_price) } else { (used_bid, used_ask) };
+        if let Some(p) = book.price_for_volume_ahead(Side::Buy, volume) {
+            let join_price = p as f64 / d.price_factor;
+            if bid_ref - bid_offset > join_price {
+                info!("not stepping in front of thin bids, joining the bid at {}", join_price);
+                bid_offset = bid_ref - join_price;
+            }
+        }
+        if let Some(p) = book.price_for_volume_ahead(Side::Sell, volume) {
+            let join_price = p as f64 / d.price_factor;
+            if ask_ref + ask_offset < join_price {
+                info!("not stepping in front of thin asks, joining the ask at {}", join_price);
+                ask_offset = join_price - ask_ref;
+            }
+        }
+    }
+
     let worst_bid_offset = (used_mid_price as f64) * (c.price_range_factor - 0.001); // we remove 0.5 % from what's allowed to be more safely inside
     bid_offset = bid_offset.min(worst_bid_offset);
     let worst_ask_offset = (used_mid_price as f64) * (c.price_range_factor - 0.001);        
@@ -346,33 +510,70 @@ async fn run_strategy(
     
 
 
-    let batch_w1 = Command::BatchMarketInstructions(
-        get_batch(
-            c.vega_market.clone(),
-            used_mid_price,
-            used_bid,
-            vega_best_bid,
-            bid_offset,
-            bid_side_situation,
-            worst_bid_offset,
-            used_ask,
-            vega_best_ask,
-            ask_offset,
-            ask_side_situation,
-            worst_ask_offset,
-            c.levels,
-            c.step,
-            c.tick_size,
-            c.volume_of_notional,
-            c.buy_to_sell_ratio,
-            &d,
-            c.use_mid,
-            c.allow_negative_offset,
-            c.gtt_length,
-            dispose_of_short_pos,
-            dispose_of_long_pos,
-        )
+    let mut volume_of_notional = c.volume_of_notional;
+    let balances = store.lock().unwrap().get_balances(&asset.id);
+    if let Some(b) = balances {
+        if c.bond_amount > 0 && !bond_is_funded(&b, c.bond_amount, &d) {
+            info!(
+                "bond account holds {} but the commitment is {}, top it up or amend liquidity",
+                b.bond / d.asset_factor,
+                c.bond_amount
+            );
+        }
+        // the margin account holds what our current orders use, and those get replaced
+        let budget = (b.general + b.margin) / d.asset_factor;
+        volume_of_notional = affordable_volume_of_notional(c.volume_of_notional, budget, c.margin_per_notional);
+        if volume_of_notional < c.volume_of_notional {
+            info!(
+                "general + margin balance {:.2} only covers {} of volume_of_notional {}, scaling down",
+                budget, volume_of_notional, c.volume_of_notional
+            );
+        }
+        if volume_of_notional == 0 {
+            info!("no balance to quote with, pulling our quotes.");
+            pull_quotes(w1, c, old_rp).await;
+            return;
+        }
+    }
+
+    let mut batch = get_batch(
+        c.vega_market.clone(),
+        used_mid_price,
+        used_bid,
+        vega_best_bid,
+        bid_offset,
+        bid_side_situation,
+        worst_bid_offset,
+        used_ask,
+        vega_best_ask,
+        ask_offset,
+        ask_side_situation,
+        worst_ask_offset,
+        c.levels,
+        c.step,
+        c.tick_size,
+        volume_of_notional,
+        c.buy_to_sell_ratio,
+        &d,
+        c.use_mid,
+        c.allow_negative_offset,
+        c.gtt_length,
+        dispose_of_short_pos,
+        dispose_of_long_pos,
     );
+    if c.incremental_orders {
+        let live = store.lock().unwrap().get_my_orders();
+        // not taken from the ladder, which is empty when both sides are pulled
+        let expires_at = gtt_expires_at(c.gtt_length);
+        // push out the expiry of orders that would otherwise lapse before the next full refresh
+        let refresh_before = expires_at - (c.gtt_length as i64 * 1_000_000_000) / 2;
+        batch = order_diff::diff_orders(&c.vega_market, batch.submissions, live, refresh_before);
+        if order_diff::is_empty(&batch) {
+            info!("live orders already match the ladder, nothing to send");
+            return;
+        }
+    }
+    let batch_w1 = Command::BatchMarketInstructions(batch);
     if !c.dryrun {
         match w1.send(batch_w1).await {
             Ok(o) => info!("w1 result: {:?}", o),
@@ -385,6 +586,22 @@ async fn run_strategy(
 }
 
 
+// expiry in nanos since the unix epoch for an order placed now
+fn gtt_expires_at(gtt_length: u64) -> i64 {
+    // Get the current time
+    let current_time = SystemTime::now();
+
+    // Get the duration since the UNIX epoch
+    let duration_since_epoch = current_time.duration_since(UNIX_EPOCH).expect("Time went backwards");
+
+    // Add some time to current time
+    let duration_since_epoch_plus_extra = duration_since_epoch + Duration::from_secs(gtt_length);
+
+    // Get the duration in nanoseconds
+    return duration_since_epoch_plus_extra.as_nanos() as i64;
+}
+
+
 fn get_batch(
     market_id: String,
     mid_price: f64,
@@ -414,17 +631,7 @@ fn get_batch(
     
     let (tif, typ) = (TimeInForce::Gtt, Type::Limit);
     
-    // Get the current time
-    let current_time = SystemTime::now();
-
-    // Get the duration since the UNIX epoch
-    let duration_since_epoch = current_time.duration_since(UNIX_EPOCH).expect("Time went backwards");
-
-    // Add some time to current time
-    let duration_since_epoch_plus_extra = duration_since_epoch + Duration::from_secs(gtt_length);
-
-    // Get the duration in nanoseconds
-    let expires_at = duration_since_epoch_plus_extra.as_nanos() as i64;
+    let expires_at = gtt_expires_at(gtt_length);
 
     let mut orders: Vec<OrderSubmission> = vec![];
     
@@ -468,7 +675,7 @@ fn get_batch(
                 pegged_order: None,
                 price: price_sub.to_string(),
                 size: size,
-                reference: "".to_string(),
+                reference: ladder_reference(side, i),
                 side: side.into(),
                 time_in_force: tif.into(),
                 r#type: typ.into(),
@@ -502,7 +709,7 @@ fn get_batch(
             pegged_order: None,
             price: price_sub.to_string(),
             size: size,
-            reference: "".to_string(),
+            reference: ladder_reference(side, 0),
             side: side.into(),
             time_in_force: tif.into(),
             r#type: typ.into(),
@@ -543,7 +750,7 @@ fn get_batch(
                 pegged_order: None,
                 price: price_sub.to_string(),
                 size: size as u64,
-                reference: "".to_string(),
+                reference: ladder_reference(side, i),
                 side: side.into(),
                 time_in_force: tif.into(),
                 r#type: typ.into(),
@@ -577,7 +784,7 @@ fn get_batch(
             pegged_order: None,
             price: price_sub.to_string(),
             size: size,
-            reference: "".to_string(),
+            reference: ladder_reference(side, 0),
             side: side.into(),
             time_in_force: tif.into(),
             r#type: typ.into(),
@@ -588,7 +795,8 @@ fn get_batch(
     }
     
     if dispose_of_short_pos {
-        // we're setting up on order that will reduce our position (or so we hope)
+        // we're setting up on order that will reduce our position (or so we hope),
+        // its slot is the one past the end of the ladder
         let price = sell_side_ref_price + ask_offset - 1.0/d.price_factor;
         let mut price_sub = (price * d.price_factor) as i64;
         price_sub -= price_sub % ((tick * d.price_factor) as i64);
@@ -605,7 +813,7 @@ fn get_batch(
             pegged_order: None,
             price: price_sub.to_string(),
             size: size as u64,
-            reference: "".to_string(),
+            reference: ladder_reference(Side::Buy, num_levels),
             side: Side::Buy.into(),
             time_in_force: tif.into(),
             r#type: typ.into(),
@@ -632,7 +840,7 @@ fn get_batch(
             pegged_order: None,
             price: price_sub.to_string(),
             size: size as u64,
-            reference: "".to_string(),
+            reference: ladder_reference(Side::Sell, num_levels),
             side: Side::Sell.into(),
             time_in_force: tif.into(),
             r#type: typ.into(),
@@ -657,6 +865,124 @@ fn get_batch(
     };
 }
 
+// Caps the notional per side so the margin for our orders fits in the
+// balance we have, margin_per_notional is the initial margin we expect per
+// unit of notional. 0 turns the cap off.
+fn affordable_volume_of_notional(volume_of_notional: u64, balance: f64, margin_per_notional: f64) -> u64 {
+    if margin_per_notional <= 0.0 {
+        return volume_of_notional;
+    }
+    let affordable = (balance / margin_per_notional).max(0.0);
+    return (volume_of_notional as f64).min(affordable).floor() as u64;
+}
+
+// Feeds the P&L engine what the store got since the last cycle, checks it
+// against the positions stream and logs it.
+fn update_pnl(
+    pnl: &mut PnlEngine,
+    store: &Arc<Mutex<VegaStore>>,
+    party_id: &str,
+    asset_id: &str,
+    max_pnl_drift: f64,
+    d: &Decimals,
+) {
+    let (fills, md, position, balances) = {
+        let s = store.lock().unwrap();
+        (
+            s.get_fills_after(pnl.fills_cursor()),
+            s.get_market_data(),
+            s.get_position(party_id),
+            s.get_balances(asset_id),
+        )
+    };
+    for f in fills.iter() {
+        pnl.apply_fill(f);
+    }
+    // what's left is for the kappa estimate and the fill stats
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_nanos() as u64;
+    store
+        .lock()
+        .unwrap()
+        .prune_fills(now.saturating_sub(ESTIMATION_WINDOW.as_nanos() as u64), pnl.fills_cursor());
+    pnl.apply_market_data(&md);
+    if let Some(b) = balances {
+        pnl.update_lp_fees(b.lp_fees);
+    }
+    if let Some(p) = position.as_ref() {
+        match pnl.reconcile(p) {
+            Some(drift) if drift.exceeds(max_pnl_drift, d.position_factor) => {
+                warn!("P&L drifted from the positions stream: {:?}", drift)
+            }
+            _ => {}
+        }
+    }
+
+    let (session, today) = (pnl.session(), pnl.today());
+    info!(
+        "P&L position: {} @ {:.5}, mark: {:.5}, unrealised: {:.4}",
+        pnl.position(),
+        pnl.avg_entry(),
+        pnl.mark(),
+        pnl.unrealised()
+    );
+    info!(
+        "P&L session: net {:.4} (realised {:.4}, fees paid {:.4}, maker fees {:.4}, lp fees {:.4}, funding {:.4}), today: net {:.4} (realised {:.4}, fees paid {:.4}, maker fees {:.4}, lp fees {:.4}, funding {:.4})",
+        session.net(), session.realised, session.fees_paid, session.maker_fees, session.lp_fees, session.funding,
+        today.net(), today.realised, today.fees_paid, today.maker_fees, today.lp_fees, today.funding
+    );
+}
+
+fn risk_inputs(pnl: &PnlEngine, store: &Arc<Mutex<VegaStore>>, positions_loaded: bool, d: &Decimals) -> RiskInputs {
+    let orders = store.lock().unwrap().get_my_orders();
+    let mut order_notional = 0.0;
+    for o in orders.iter() {
+        // pegged orders without a price yet count once they have one
+        let price = o.price.parse::<f64>().unwrap_or_default() / d.price_factor;
+        order_notional += price * o.remaining as f64 / d.position_factor;
+    }
+    return RiskInputs {
+        positions_loaded,
+        equity: pnl.session().net() + pnl.unrealised(),
+        position_notional: pnl.position().abs() * pnl.mark(),
+        order_notional,
+    };
+}
+
+pub async fn cancel_all(w1: &mut Transact, c: &Config) {
+    if !c.dryrun {
+        match w1
+            .send(Command::BatchMarketInstructions(get_close_batch(
+                c.vega_market.clone(),
+            )))
+            .await
+        {
+            Ok(o) => info!("cancel orders batch result: {:?}", o),
+            Err(e) => info!("cancel orders transaction error: {:?}", e),
+        };
+    }
+    else {
+        info!("dryrun mode, would submit a close orders transaction");
+    }
+}
+
+// Cancel everything we have on the book, but only once: old_rp is cleared so
+// we don't keep sending cancellations while the sources stay stale and so the
+// next fresh price always triggers a new submission.
+async fn pull_quotes(
+    w1: &mut Transact,
+    c: &Config,
+    old_rp: Arc<Mutex<RefPrice>>,
+) {
+    let (old_ref_bid, old_ref_ask) = old_rp.lock().unwrap().get();
+    if old_ref_bid <= 0.0 && old_ref_ask <= 0.0 {
+        info!("quotes already pulled, nothing to cancel");
+        return;
+    }
+
+    cancel_all(w1, c).await;
+    old_rp.lock().unwrap().clear();
+}
+
 fn get_close_batch(market_id: String) -> BatchMarketInstructions {
     return BatchMarketInstructions {
         cancellations: vec![OrderCancellation {
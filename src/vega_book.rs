@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use vega_protobufs::vega::{MarketDepthUpdate, Order, PriceLevel, Side};
+
+// Full depth Vega order book, prices in market decimals and volumes in
+// position decimals. Built from a market depth snapshot and kept up to date
+// with the depth updates stream, each update names the sequence number it
+// follows so we can tell when we missed one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VegaBook {
+    // price -> volume
+    bids: BTreeMap<u64, u64>,
+    asks: BTreeMap<u64, u64>,
+    sequence_number: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SequenceGap {
+    pub expected: u64,
+    pub got: u64,
+}
+
+fn set_level(levels: &mut BTreeMap<u64, u64>, level: &PriceLevel) {
+    let price = match level.price.parse::<u64>() {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    if level.volume == 0 {
+        levels.remove(&price);
+    } else {
+        levels.insert(price, level.volume);
+    }
+}
+
+impl VegaBook {
+    pub fn new() -> VegaBook {
+        return VegaBook::default();
+    }
+
+    pub fn from_levels(buy: &Vec<PriceLevel>, sell: &Vec<PriceLevel>, sequence_number: u64) -> VegaBook {
+        let mut book = VegaBook::new();
+        for l in buy.iter() {
+            set_level(&mut book.bids, l);
+        }
+        for l in sell.iter() {
+            set_level(&mut book.asks, l);
+        }
+        book.sequence_number = sequence_number;
+        return book;
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        return self.sequence_number;
+    }
+
+    // Ok(false) for updates the snapshot already covers, a SequenceGap if we
+    // missed one; the book then needs a fresh snapshot.
+    pub fn apply_update(&mut self, u: &MarketDepthUpdate) -> Result<bool, SequenceGap> {
+        if u.sequence_number <= self.sequence_number {
+            return Ok(false);
+        }
+        if u.previous_sequence_number != self.sequence_number {
+            return Err(SequenceGap {
+                expected: self.sequence_number,
+                got: u.previous_sequence_number,
+            });
+        }
+        for l in u.buy.iter() {
+            set_level(&mut self.bids, l);
+        }
+        for l in u.sell.iter() {
+            set_level(&mut self.asks, l);
+        }
+        self.sequence_number = u.sequence_number;
+        return Ok(true);
+    }
+
+    pub fn best_bid(&self) -> Option<(u64, u64)> {
+        return self.bids.iter().next_back().map(|(p, v)| (*p, *v));
+    }
+
+    pub fn best_ask(&self) -> Option<(u64, u64)> {
+        return self.asks.iter().next().map(|(p, v)| (*p, *v));
+    }
+
+    // (price, volume) on one side, best first
+    pub fn levels(&self, side: Side) -> Vec<(u64, u64)> {
+        match side {
+            Side::Buy => return self.bids.iter().rev().map(|(p, v)| (*p, *v)).collect(),
+            _ => return self.asks.iter().map(|(p, v)| (*p, *v)).collect(),
+        }
+    }
+
+    // Volume that would trade before an order of ours at `price` on `side`:
+    // everything at a better price plus the queue already at that price.
+    pub fn volume_ahead(&self, side: Side, price: u64) -> u64 {
+        match side {
+            Side::Buy => return self.bids.range(price..).map(|(_, v)| v).sum(),
+            _ => return self.asks.range(..=price).map(|(_, v)| v).sum(),
+        }
+    }
+
+    // Worst price we'd trade at to fill `size` with an aggressive order on
+    // `side`, None if the book isn't deep enough.
+    pub fn price_to_fill(&self, side: Side, size: u64) -> Option<u64> {
+        let opposite = match side {
+            Side::Buy => Side::Sell,
+            _ => Side::Buy,
+        };
+        return self.price_for_volume(opposite, size);
+    }
+
+    // The best price on `side` with at least `volume` at it or in front of it,
+    // i.e. where a passive order of ours would have that much queue ahead.
+    pub fn price_for_volume_ahead(&self, side: Side, volume: u64) -> Option<u64> {
+        return self.price_for_volume(side, volume);
+    }
+
+    fn price_for_volume(&self, side: Side, volume: u64) -> Option<u64> {
+        let mut cumulative = 0;
+        for (price, v) in self.levels(side).into_iter() {
+            cumulative += v;
+            if cumulative >= volume {
+                return Some(price);
+            }
+        }
+        return None;
+    }
+
+    // the book as everybody else sees it, without our own remaining volume
+    pub fn excluding(&self, orders: &Vec<Order>) -> VegaBook {
+        let mut book = self.clone();
+        for o in orders.iter() {
+            let price = match o.price.parse::<u64>() {
+                Ok(p) => p,
+                // pegged orders without a price yet
+                Err(_) => continue,
+            };
+            let levels = if o.side == Side::Buy as i32 { &mut book.bids } else { &mut book.asks };
+            if let Some(v) = levels.get_mut(&price) {
+                *v = v.saturating_sub(o.remaining);
+                if *v == 0 {
+                    levels.remove(&price);
+                }
+            }
+        }
+        return book;
+    }
+}
+
+
+mod tests {
+    use super::*;
+
+    fn level(price: u64, volume: u64) -> PriceLevel {
+        return PriceLevel {
+            price: price.to_string(),
+            volume,
+            ..Default::default()
+        };
+    }
+
+    fn book() -> VegaBook {
+        return VegaBook::from_levels(
+            &vec![level(100, 5), level(99, 10), level(98, 20)],
+            &vec![level(101, 3), level(102, 7), level(104, 30)],
+            10,
+        );
+    }
+
+    #[test]
+    fn test_queries() {
+        let b = book();
+        assert_eq!(b.best_bid(), Some((100, 5)));
+        assert_eq!(b.best_ask(), Some((101, 3)));
+        assert_eq!(b.volume_ahead(Side::Buy, 99), 15);
+        assert_eq!(b.volume_ahead(Side::Sell, 103), 10);
+        assert_eq!(b.price_to_fill(Side::Buy, 10), Some(102));
+        assert_eq!(b.price_to_fill(Side::Sell, 36), None);
+        assert_eq!(b.price_for_volume_ahead(Side::Buy, 6), Some(99));
+    }
+
+    #[test]
+    fn test_updates_and_gaps() {
+        let mut b = book();
+        let update = |seq: u64, prev: u64, buy: Vec<PriceLevel>| MarketDepthUpdate {
+            sequence_number: seq,
+            previous_sequence_number: prev,
+            buy,
+            ..Default::default()
+        };
+
+        // already in the snapshot
+        assert_eq!(b.apply_update(&update(9, 8, vec![level(100, 0)])), Ok(false));
+        assert_eq!(b.apply_update(&update(11, 10, vec![level(100, 0), level(99, 12)])), Ok(true));
+        assert_eq!(b.best_bid(), Some((99, 12)));
+        assert_eq!(
+            b.apply_update(&update(14, 13, vec![])),
+            Err(SequenceGap { expected: 11, got: 13 })
+        );
+    }
+
+    #[test]
+    fn test_excluding_own_orders() {
+        let mine = Order {
+            price: "100".to_string(),
+            remaining: 5,
+            side: Side::Buy.into(),
+            ..Default::default()
+        };
+        let partly_mine = Order {
+            price: "102".to_string(),
+            remaining: 2,
+            side: Side::Sell.into(),
+            ..Default::default()
+        };
+        let others = book().excluding(&vec![mine, partly_mine]);
+        assert_eq!(others.best_bid(), Some((99, 10)));
+        assert_eq!(others.levels(Side::Sell), vec![(101, 3), (102, 5), (104, 30)]);
+    }
+}
@@ -1,21 +1,32 @@
 use chrono::{DateTime, Local, NaiveDateTime, Utc};
-use log::{error, info};
+use log::{error, info, warn};
 use lru::LruCache;
 use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, timeout};
 use tokio_stream::StreamExt;
 use tonic;
 use vega_protobufs::datanode::api::v2::GetLatestMarketDataRequest;
 use vega_protobufs::vega::MarketData;
 
+use crate::backoff::Backoff;
+use crate::fills::{fills_from_trade, Fill};
+use crate::node_pool::{node_switched, NodePool};
+use crate::price_source::PriceSource;
+use crate::ref_price::RefPrice;
+use crate::vega_book::{SequenceGap, VegaBook};
+
 use vega_protobufs::{
     datanode::api::v2::{
-        trading_data_service_client::TradingDataServiceClient, GetMarketRequest, ListAssetsRequest,
-        ObserveMarketsDataRequest, ObservePositionsRequest, ObserveTradesRequest,
+        trading_data_service_client::TradingDataServiceClient, DateRange, GetMarketDataHistoryByIdRequest,
+        GetMarketRequest, ListAssetsRequest, ListTradesRequest, Pagination,
+        AccountBalance, GetLatestMarketDepthRequest, ObserveAccountsRequest, ObserveMarketsDataRequest,
+        ObserveMarketsDepthUpdatesRequest, ObserveOrdersRequest, ObservePositionsRequest, ObserveTradesRequest,
     },
-    vega::{Asset, Market, Position, Trade},
+    vega::{order, AccountType, Asset, Market, MarketDepthUpdate, Order, Position, Side, Trade},
 };
 
 #[derive(Clone)]
@@ -25,23 +36,99 @@ pub struct TradeStat {
     pub size: u64,
     pub block_best_bid: f64,
     pub block_best_ask: f64,
+    // Unspecified for trades without an aggressor, e.g. auction uncrossing
+    pub aggressor: Side,
+    pub buyer: String,
+    pub seller: String,
+    // we were the buyer or the seller
+    pub is_ours: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VegaStream {
+    MarketData,
+    Positions,
+    Trades,
+    Orders,
+    Accounts,
+    Depth,
+    Fills,
+}
+
+impl VegaStream {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VegaStream::MarketData => "market_data",
+            VegaStream::Positions => "positions",
+            VegaStream::Trades => "trades",
+            VegaStream::Orders => "orders",
+            VegaStream::Accounts => "accounts",
+            VegaStream::Depth => "depth",
+            VegaStream::Fills => "fills",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamStatus {
+    pub connected: bool,
+    // last message (or snapshot) received, None until the first one
+    pub last_update: Option<Instant>,
+    pub reconnects: u64,
 }
 
+impl StreamStatus {
+    fn new() -> StreamStatus {
+        return StreamStatus {
+            connected: false,
+            last_update: None,
+            reconnects: 0,
+        };
+    }
+}
+
+// Our account balances for one asset, in asset decimals. Margin, bond and
+// LP fees are the accounts for our market only.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Balances {
+    pub general: f64,
+    pub margin: f64,
+    pub bond: f64,
+    pub lp_fees: f64,
+}
 
 pub struct VegaStore {
+    // our party ID (public key)
+    party_id: String,
     market: Market,
     market_data: MarketData,
     previous_market_data: LruCache<i64, MarketData>,
     trades: Vec<TradeStat>,
+    // our own fills of the last estimation window and those the P&L hasn't
+    // applied yet, oldest first
+    fills: Vec<Fill>,
+    // seq of the last fill we saved
+    fill_seq: u64,
     positions: HashMap<String, Position>,
+    // full depth book, empty until the depth stream loaded a snapshot
+    book: VegaBook,
+    // our live orders, key = order ID
+    orders: HashMap<String, Order>,
     // key = asset ID
     assets: HashMap<String, Asset>,
+    // key = asset ID, empty until the accounts snapshot arrived
+    balances: HashMap<String, Balances>,
+    accounts_loaded: bool,
+    // the positions snapshot arrived, until then no position means unknown
+    positions_loaded: bool,
+    streams: HashMap<VegaStream, StreamStatus>,
 }
 
 impl VegaStore {
     pub async fn new(
         clt: &mut TradingDataServiceClient<tonic::transport::Channel>,
         mkt_id: &str,
+        party_id: &str,
     ) -> Result<VegaStore, Error> {
         // info!("1");
         let mkt_resp = clt
@@ -82,12 +169,21 @@ impl VegaStore {
 
         previous_market_data.put(market_data.timestamp, market_data.clone());
         return Ok(VegaStore {
+            party_id: party_id.to_string(),
             market,
             market_data,
             previous_market_data,
             assets,
             positions,
+            book: VegaBook::new(),
+            orders: HashMap::new(),
+            balances: HashMap::new(),
+            accounts_loaded: false,
+            positions_loaded: false,
             trades: vec![],
+            fills: vec![],
+            fill_seq: 0,
+            streams: HashMap::new(),
         });
     }
 
@@ -111,12 +207,153 @@ impl VegaStore {
         return self.assets.clone().into_values().collect();
     }
 
+    pub fn get_stream_status(&self, stream: VegaStream) -> StreamStatus {
+        return self.streams.get(&stream).cloned().unwrap_or(StreamStatus::new());
+    }
+
+    fn set_stream_connected(&mut self, stream: VegaStream, connected: bool) {
+        let status = self.streams.entry(stream).or_insert(StreamStatus::new());
+        if connected && status.last_update.is_some() {
+            status.reconnects += 1;
+        }
+        status.connected = connected;
+        if connected {
+            status.last_update = Some(Instant::now());
+        }
+    }
+
+    fn touch_stream(&mut self, stream: VegaStream) {
+        self.streams.entry(stream).or_insert(StreamStatus::new()).last_update = Some(Instant::now());
+    }
+
+    pub fn stream_up(&self, stream: VegaStream) -> bool {
+        return self.get_stream_status(stream).connected;
+    }
+
+    // Reasons not to quote right now, empty if we can. Only the streams we
+    // can't do without count: the market data, our position, and our live
+    // orders when we diff against them; the strategy falls back to the config
+    // for what the others feed. Market data comes every block so it must also
+    // be fresh, the other streams only send something when they change so for
+    // those we only need the stream to be up.
+    pub fn unhealthy_streams(&self, max_market_data_age: Duration, incremental_orders: bool) -> Vec<String> {
+        let mut reasons = vec![];
+        let mut required = vec![VegaStream::MarketData, VegaStream::Positions];
+        if incremental_orders {
+            required.push(VegaStream::Orders);
+        }
+        for stream in required {
+            let status = self.get_stream_status(stream);
+            if !status.connected {
+                reasons.push(format!("{} stream is disconnected", stream.name()));
+                continue;
+            }
+            if stream != VegaStream::MarketData {
+                continue;
+            }
+            match status.last_update {
+                Some(t) if t.elapsed() <= max_market_data_age => {}
+                Some(t) => reasons.push(format!(
+                    "no {} update for {:.1}s",
+                    stream.name(),
+                    t.elapsed().as_secs_f64()
+                )),
+                None => reasons.push(format!("no {} update yet", stream.name())),
+            }
+        }
+        return reasons;
+    }
+
     pub fn save_positions(&mut self, positions: Vec<Position>) {
         for p in positions.into_iter() {
             self.positions.insert(p.party_id.clone(), p.clone());
         }
     }
 
+    // full snapshot from a (re)connected positions stream
+    pub fn replace_positions(&mut self, positions: Vec<Position>) {
+        self.positions.clear();
+        self.save_positions(positions);
+        self.positions_loaded = true;
+    }
+
+    pub fn positions_loaded(&self) -> bool {
+        return self.positions_loaded;
+    }
+
+    pub fn get_book(&self) -> VegaBook {
+        return self.book.clone();
+    }
+
+    pub fn get_book_excluding_own(&self) -> VegaBook {
+        return self.book.excluding(&self.get_my_orders());
+    }
+
+    pub fn set_book(&mut self, book: VegaBook) {
+        self.book = book;
+    }
+
+    pub fn apply_depth_update(&mut self, u: &MarketDepthUpdate) -> Result<bool, SequenceGap> {
+        return self.book.apply_update(u);
+    }
+
+    pub fn get_my_orders(&self) -> Vec<Order> {
+        return self.orders.values().cloned().collect();
+    }
+
+    // order updates, orders that are no longer on the book are dropped
+    pub fn save_orders(&mut self, orders: Vec<Order>) {
+        for o in orders.into_iter() {
+            if is_live(&o) {
+                self.orders.insert(o.id.clone(), o);
+            } else {
+                self.orders.remove(&o.id);
+            }
+        }
+    }
+
+    // full snapshot from a (re)connected orders stream
+    pub fn replace_orders(&mut self, orders: Vec<Order>) {
+        self.orders.clear();
+        self.save_orders(orders);
+    }
+
+    // None until we got the accounts snapshot, zero balances if we have no
+    // account in that asset
+    pub fn get_balances(&self, asset_id: &str) -> Option<Balances> {
+        if !self.accounts_loaded {
+            return None;
+        }
+        return Some(self.balances.get(asset_id).cloned().unwrap_or_default());
+    }
+
+    pub fn save_accounts(&mut self, accounts: Vec<AccountBalance>) {
+        for a in accounts.into_iter() {
+            // general accounts have no market, everything else must be ours
+            if !a.market_id.is_empty() && a.market_id != self.market.id {
+                continue;
+            }
+            let balance = a.balance.parse::<f64>().unwrap_or_default();
+            let b = self.balances.entry(a.asset.clone()).or_default();
+            if a.r#type == AccountType::General as i32 {
+                b.general = balance;
+            } else if a.r#type == AccountType::Margin as i32 {
+                b.margin = balance;
+            } else if a.r#type == AccountType::Bond as i32 {
+                b.bond = balance;
+            } else if a.r#type == AccountType::LpLiquidityFees as i32 {
+                b.lp_fees = balance;
+            }
+        }
+    }
+
+    // full snapshot from a (re)connected accounts stream
+    pub fn replace_accounts(&mut self, accounts: Vec<AccountBalance>) {
+        self.balances.clear();
+        self.save_accounts(accounts);
+        self.accounts_loaded = true;
+    }
+
     pub fn save_market_data(&mut self, md: MarketData) {
         self.update_trades(&md);
         self.previous_market_data.put(md.timestamp, md.clone());
@@ -124,6 +361,15 @@ impl VegaStore {
     }
 
     pub fn update_trades(&mut self, md: &MarketData) {
+        for f in self.fills.iter_mut().rev() {
+            if f.timestamp < md.timestamp as u64 {
+                break;
+            }
+            if f.timestamp == md.timestamp as u64 {
+                f.block_best_bid = md.best_bid_price.parse::<f64>().unwrap_or_default();
+                f.block_best_ask = md.best_offer_price.parse::<f64>().unwrap_or_default();
+            }
+        }
         for t in self.trades.iter_mut() {
             if t.timestamp == md.timestamp as u64 {
                 match md.best_offer_price.clone().to_string().parse::<f64>() {
@@ -148,6 +394,41 @@ impl VegaStore {
     }
 
     pub fn save_trade(&mut self, trade: &Trade) {
+        let stat = match self.trade_stat(trade) {
+            Some(t) => t,
+            None => return,
+        };
+
+        info!(
+            "TRADE with time: {}, price: {}, size: {}, aggressor: {:?}, best_bid: {}, best_ask: {}, ours: {}",
+            convert_nanos_since_unix_epoch_datetime(trade.timestamp as u64),
+            stat.price,
+            stat.size,
+            stat.aggressor,
+            stat.block_best_bid,
+            stat.block_best_ask,
+            stat.is_ours
+        );
+        self.trades.push(stat);
+    }
+
+    // trades loaded at startup, oldest first; too many to log one by one
+    pub fn save_historical_trades(&mut self, trades: &Vec<Trade>) {
+        for trade in trades.iter() {
+            if let Some(t) = self.trade_stat(trade) {
+                self.trades.push(t);
+            }
+        }
+    }
+
+    // market data of past blocks, so trades can be matched with their block's best bid / ask
+    pub fn save_historical_market_data(&mut self, market_data: Vec<MarketData>) {
+        for md in market_data.into_iter() {
+            self.previous_market_data.put(md.timestamp, md);
+        }
+    }
+
+    fn trade_stat(&mut self, trade: &Trade) -> Option<TradeStat> {
         let (best_bid_f, best_ask_f) = match self.previous_market_data.get(&trade.timestamp) {
             Some(md) => (
                 md.best_bid_price.parse::<f64>().unwrap_or_default(),
@@ -160,29 +441,80 @@ impl VegaStore {
             Ok(v) => v,
             Err(_) => {
                 error!("trade with no price: {:?}", trade);
-                return;
+                return None;
             }
         };
 
-        info!(
-            "TRADE with time: {}, price: {}, size: {}, aggressor: {}, best_bid: {}, best_ask: {}",
-            convert_nanos_since_unix_epoch_datetime(trade.timestamp as u64),
-            price_f,
-            trade.size,
-            trade.aggressor,
-            best_bid_f,
-            best_ask_f
-        );
-
-        self.trades.push(TradeStat {
+        return Some(TradeStat {
             timestamp: trade.timestamp as u64,
             price: price_f,
             size: trade.size,
             block_best_ask: best_ask_f,
             block_best_bid: best_bid_f,
+            aggressor: Side::try_from(trade.aggressor).unwrap_or(Side::Unspecified),
+            buyer: trade.buyer.clone(),
+            seller: trade.seller.clone(),
+            is_ours: trade.buyer == self.party_id || trade.seller == self.party_id,
         });
     }
 
+    // Trades from the party filtered stream or its backfill, a trade we
+    // already have is skipped.
+    pub fn save_fills(&mut self, trade: &Trade) {
+        let (best_bid, best_ask) = match self.previous_market_data.get(&trade.timestamp) {
+            Some(md) => (
+                md.best_bid_price.parse::<f64>().unwrap_or_default(),
+                md.best_offer_price.parse::<f64>().unwrap_or_default(),
+            ),
+            None => (0.0, 0.0),
+        };
+        for mut f in fills_from_trade(trade, &self.party_id, best_bid, best_ask).into_iter() {
+            let seen = self
+                .fills
+                .iter()
+                .rev()
+                .take_while(|old| old.timestamp >= f.timestamp)
+                .any(|old| old.trade_id == f.trade_id && old.side == f.side);
+            if seen {
+                continue;
+            }
+            info!(
+                "FILL {:?} {} @ {} order: {}, fee: {}, maker: {}",
+                f.side, f.size, f.price, f.order_id, f.fee, f.is_maker
+            );
+            // backfilled trades can be older than the last one we have
+            self.fill_seq += 1;
+            f.seq = self.fill_seq;
+            let idx = self.fills.partition_point(|old| old.timestamp <= f.timestamp);
+            self.fills.insert(idx, f);
+        }
+    }
+
+    pub fn get_fills(&self) -> Vec<Fill> {
+        return self.fills.clone();
+    }
+
+    // fills saved after the one with this seq, oldest first
+    pub fn get_fills_after(&self, seq: u64) -> Vec<Fill> {
+        return self.fills.iter().filter(|f| f.seq > seq).cloned().collect();
+    }
+
+    // Drops the fills older than timestamp that are applied up to
+    // applied_seq. The newest fills are always kept, save_fills needs them
+    // to drop the duplicates of a backfill.
+    pub fn prune_fills(&mut self, timestamp: u64, applied_seq: u64) {
+        let newest = match self.fills.last() {
+            Some(f) => f.timestamp,
+            None => return,
+        };
+        self.fills
+            .retain(|f| f.timestamp >= timestamp || f.seq > applied_seq || f.timestamp == newest);
+    }
+
+    pub fn last_fill_timestamp(&self) -> Option<u64> {
+        return self.fills.last().map(|f| f.timestamp);
+    }
+
     pub fn get_trades(&self) -> Vec<TradeStat> {
         return self.trades.clone();
     }
@@ -208,132 +540,728 @@ impl VegaStore {
     }
 }
 
+// active orders and parked ones (pegged orders during auctions) are still ours
+fn is_live(o: &Order) -> bool {
+    return o.status == order::Status::Active as i32 || o.status == order::Status::Parked as i32;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VegaPriceKind {
+    // best bid / ask of the vega book
+    BestBidAsk,
+    // the rest are single prices quoted with a configured half spread
+    Mark,
+    // external (oracle) TWAP of a perpetual
+    ExternalTwap,
+    // uncrossing price, only set during auctions
+    Indicative,
+}
+
+// Vega market data as a reference price source
+pub struct VegaPriceSource {
+    store: Arc<Mutex<VegaStore>>,
+    kind: VegaPriceKind,
+    half_spread_bp: f64,
+    price_factor: f64,
+    position_factor: f64,
+}
+
+impl VegaPriceSource {
+    pub fn new(store: Arc<Mutex<VegaStore>>, kind: VegaPriceKind, half_spread_bp: f64) -> VegaPriceSource {
+        let mkt = store.lock().unwrap().get_market();
+        return VegaPriceSource {
+            store,
+            kind,
+            half_spread_bp,
+            price_factor: (10_f64).powf(mkt.decimal_places as f64),
+            position_factor: (10_f64).powf(mkt.position_decimal_places as f64),
+        };
+    }
+}
+
+// single reference price in market decimals, None if not set
+fn single_price(md: &MarketData, kind: VegaPriceKind) -> Option<f64> {
+    use vega_protobufs::vega::product_data::Data;
+
+    let price_str = match kind {
+        VegaPriceKind::BestBidAsk => return None,
+        VegaPriceKind::Mark => md.mark_price.clone(),
+        VegaPriceKind::Indicative => md.indicative_price.clone(),
+        VegaPriceKind::ExternalTwap => match md.product_data.as_ref().and_then(|p| p.data.as_ref()) {
+            Some(Data::PerpetualData(perp)) => perp.external_twap.clone(),
+            _ => return None,
+        },
+    };
+    let price = price_str.parse::<f64>().unwrap_or_default();
+    if price <= 0.0 {
+        return None;
+    }
+    return Some(price);
+}
+
+impl PriceSource for VegaPriceSource {
+    fn name(&self) -> String {
+        let name = match self.kind {
+            VegaPriceKind::BestBidAsk => "vega",
+            VegaPriceKind::Mark => "vega_mark",
+            VegaPriceKind::ExternalTwap => "vega_external_twap",
+            VegaPriceKind::Indicative => "vega_indicative",
+        };
+        return name.to_string();
+    }
+
+    // the price is as old as the last market data we got, so a stalled
+    // stream shows up as a stale source
+    fn latest(&self) -> Option<RefPrice> {
+        let (md, received_at) = {
+            let s = self.store.lock().unwrap();
+            (s.get_market_data(), s.get_stream_status(VegaStream::MarketData).last_update?)
+        };
+        let mut rp = RefPrice::new();
+
+        if self.kind != VegaPriceKind::BestBidAsk {
+            let price = single_price(&md, self.kind)? / self.price_factor;
+            let half_spread = price * self.half_spread_bp / 10_000.0;
+            rp.set_with_exchange_ts(price - half_spread, price + half_spread, (md.timestamp / 1_000_000) as u64);
+            rp.set_received_at(received_at);
+            return Some(rp);
+        }
+
+        let best_bid = md.best_bid_price.parse::<f64>().unwrap_or_default();
+        let best_ask = md.best_offer_price.parse::<f64>().unwrap_or_default();
+        if best_bid <= 0.0 || best_ask <= 0.0 {
+            return None;
+        }
+
+        rp.set_top(
+            best_bid / self.price_factor,
+            md.best_bid_volume as f64 / self.position_factor,
+            best_ask / self.price_factor,
+            md.best_offer_volume as f64 / self.position_factor,
+            (md.timestamp / 1_000_000) as u64,
+        );
+        rp.set_received_at(received_at);
+        return Some(rp);
+    }
+}
+
+const HISTORY_PAGE_SIZE: i32 = 1000;
+
+// Loads the last `window` of market data and trades so the lambda / kappa
+// estimates have something to work with from the first cycle. Failures are
+// only logged, we can still start with an empty trade history.
+pub async fn warm_start(
+    store: Arc<Mutex<VegaStore>>,
+    clt: &mut TradingDataServiceClient<tonic::transport::Channel>,
+    market: &str,
+    window: Duration,
+) {
+    match load_history(&store, clt, market, window).await {
+        Ok((blocks, trades)) => info!(
+            "warm start: loaded {} trades and {} blocks of market data from the last {}s",
+            trades,
+            blocks,
+            window.as_secs()
+        ),
+        Err(e) => error!("could not load trade history, starting with no trades: {}", e),
+    }
+}
+
+async fn load_history(
+    store: &Arc<Mutex<VegaStore>>,
+    clt: &mut TradingDataServiceClient<tonic::transport::Channel>,
+    market: &str,
+    window: Duration,
+) -> Result<(usize, usize), Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+    let start = (now - window).as_nanos() as i64;
+
+    let mut market_data: Vec<MarketData> = vec![];
+    let mut after: Option<String> = None;
+    loop {
+        let resp = clt
+            .get_market_data_history_by_id(GetMarketDataHistoryByIdRequest {
+                market_id: market.to_string(),
+                start_timestamp: Some(start),
+                pagination: Some(Pagination {
+                    first: Some(HISTORY_PAGE_SIZE),
+                    after: after.clone(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .await?;
+        let page = match resp.into_inner().market_data {
+            Some(p) => p,
+            None => break,
+        };
+        market_data.extend(page.edges.into_iter().filter_map(|e| e.node));
+        match page.page_info {
+            Some(p) if p.has_next_page => after = Some(p.end_cursor),
+            _ => break,
+        }
+    }
+
+    let trades = list_trades_since(clt, market, vec![], start).await?;
+
+    let (blocks, n_trades) = (market_data.len(), trades.len());
+    let mut s = store.lock().unwrap();
+    // market data first, trade_stat looks up the block of each trade
+    s.save_historical_market_data(market_data);
+    s.save_historical_trades(&trades);
+    return Ok((blocks, n_trades));
+}
+
+// All trades on the market since `start` (nanos), only those of `party_ids`
+// unless it's empty. Oldest first.
+async fn list_trades_since(
+    clt: &mut TradingDataServiceClient<tonic::transport::Channel>,
+    market: &str,
+    party_ids: Vec<String>,
+    start: i64,
+) -> Result<Vec<Trade>, Error> {
+    let mut trades: Vec<Trade> = vec![];
+    let mut after: Option<String> = None;
+    loop {
+        let resp = clt
+            .list_trades(ListTradesRequest {
+                market_ids: vec![market.to_string()],
+                party_ids: party_ids.clone(),
+                date_range: Some(DateRange {
+                    start_timestamp: Some(start),
+                    end_timestamp: None,
+                }),
+                pagination: Some(Pagination {
+                    first: Some(HISTORY_PAGE_SIZE),
+                    after: after.clone(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .await?;
+        let page = match resp.into_inner().trades {
+            Some(p) => p,
+            None => break,
+        };
+        trades.extend(page.edges.into_iter().filter_map(|e| e.node));
+        match page.page_info {
+            Some(p) if p.has_next_page => after = Some(p.end_cursor),
+            _ => break,
+        }
+    }
+    trades.sort_by_key(|t| t.timestamp);
+    return Ok(trades);
+}
+
 pub fn update_forever(
     store: Arc<Mutex<VegaStore>>,
-    clt: TradingDataServiceClient<tonic::transport::Channel>,
+    pool: Arc<Mutex<NodePool>>,
     market: &str,
     pubkey1: &str,
 ) {
     tokio::spawn(update_market_data_forever(
         store.clone(),
-        clt.clone(),
+        pool.clone(),
         market.to_string(),
     ));
     tokio::spawn(update_position_forever(
         store.clone(),
-        clt.clone(),
+        pool.clone(),
         market.to_string(),
         pubkey1.to_string(),
     ));
     tokio::spawn(update_trades_forever(
         store.clone(),
-        clt.clone(),
+        pool.clone(),
+        market.to_string(),
+    ));
+    tokio::spawn(update_fills_forever(
+        store.clone(),
+        pool.clone(),
         market.to_string(),
         pubkey1.to_string(),
     ));
+    tokio::spawn(update_orders_forever(
+        store.clone(),
+        pool.clone(),
+        market.to_string(),
+        pubkey1.to_string(),
+    ));
+    tokio::spawn(update_depth_forever(
+        store.clone(),
+        pool.clone(),
+        market.to_string(),
+    ));
+    tokio::spawn(update_accounts_forever(
+        store.clone(),
+        pool.clone(),
+        pubkey1.to_string(),
+    ));
+}
+
+// Each stream reconnects with backoff forever. While a stream is down the
+// store keeps its last state and flags the stream as disconnected, the
+// strategy checks unhealthy_streams before quoting.
+async fn reconnect_delay(store: &Arc<Mutex<VegaStore>>, stream: VegaStream, backoff: &mut Backoff) {
+    store.lock().unwrap().set_stream_connected(stream, false);
+    let delay = backoff.next_delay();
+    info!(
+        "reconnecting {} stream in {:.1}s (attempt {})",
+        stream.name(),
+        delay.as_secs_f64(),
+        backoff.attempt()
+    );
+    sleep(delay).await;
+}
+
+fn new_stream_backoff() -> Backoff {
+    return Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+}
+
+// client for the datanode currently selected in the pool, with the pool
+// generation so the stream can tell when it has to move
+async fn connect_current(
+    pool: &Arc<Mutex<NodePool>>,
+) -> Result<(TradingDataServiceClient<tonic::transport::Channel>, u64), Error> {
+    let (url, generation) = {
+        let p = pool.lock().unwrap();
+        (p.current_url(), p.generation())
+    };
+    info!("connecting to datanode {}", url);
+    let clt = TradingDataServiceClient::connect(url).await?;
+    return Ok((clt, generation));
+}
+
+// quiet streams (positions, trades) would otherwise never notice a switch
+const SWITCH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Next item of the stream, None when the stream ended or the pool moved to
+// another datanode and we have to reconnect.
+async fn next_item<T>(
+    stream: &mut tonic::Streaming<T>,
+    pool: &Arc<Mutex<NodePool>>,
+    generation: u64,
+) -> Option<Result<T, tonic::Status>> {
+    loop {
+        let item = timeout(SWITCH_CHECK_INTERVAL, stream.next()).await;
+        if node_switched(pool, generation) {
+            info!("datanode switched, dropping stream");
+            return None;
+        }
+        match item {
+            Ok(item) => return item,
+            Err(_) => continue,
+        }
+    }
 }
 
 async fn update_market_data_forever(
     store: Arc<Mutex<VegaStore>>,
-    mut clt: TradingDataServiceClient<tonic::transport::Channel>,
+    pool: Arc<Mutex<NodePool>>,
     market: String,
 ) {
-    // use vega_protobufs::datanode::api::v2::observe_markets_data_response=
-    info!("starting market_data stream for market: {}...", &*market);
-    let mut stream = match clt
+    let mut backoff = new_stream_backoff();
+    loop {
+        match run_market_data_stream(&store, &pool, &market, &mut backoff).await {
+            Ok(()) => warn!("market data stream ended"),
+            Err(e) => error!("market data stream failed: {}", e),
+        }
+        reconnect_delay(&store, VegaStream::MarketData, &mut backoff).await;
+    }
+}
+
+async fn run_market_data_stream(
+    store: &Arc<Mutex<VegaStore>>,
+    pool: &Arc<Mutex<NodePool>>,
+    market: &String,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    let (mut clt, generation) = connect_current(pool).await?;
+    info!("starting market_data stream for market: {}...", market);
+    // we may have missed blocks while disconnected, start from the latest state
+    let mktd_resp = clt
+        .get_latest_market_data(GetLatestMarketDataRequest {
+            market_id: market.clone(),
+        })
+        .await?;
+    if let Some(md) = mktd_resp.get_ref().market_data.as_ref() {
+        store.lock().unwrap().save_market_data(md.clone());
+    }
+
+    let mut stream = clt
         .observe_markets_data(ObserveMarketsDataRequest {
-            market_ids: vec![market],
+            market_ids: vec![market.clone()],
         })
-        .await
-    {
-        Ok(s) => s.into_inner(),
-        Err(e) => panic!("{:?}", e),
-    };
+        .await?
+        .into_inner();
+    store.lock().unwrap().set_stream_connected(VegaStream::MarketData, true);
 
-    while let Some(item) = stream.next().await {
-        match item {
-            Ok(resp) => {
-                for md in resp.market_data.iter() {
-                    store.lock().unwrap().save_market_data(md.clone());
-                }
-            }
-            Err(e) => {
-                error!("could not load market data: {} - {}", e, e.message());
-            }
+    while let Some(item) = next_item(&mut stream, pool, generation).await {
+        let resp = item?;
+        let mut s = store.lock().unwrap();
+        for md in resp.market_data.iter() {
+            s.save_market_data(md.clone());
         }
+        s.touch_stream(VegaStream::MarketData);
+        backoff.reset();
     }
+    return Ok(());
 }
 
 async fn update_position_forever(
     store: Arc<Mutex<VegaStore>>,
-    mut clt: TradingDataServiceClient<tonic::transport::Channel>,
+    pool: Arc<Mutex<NodePool>>,
     market: String,
     pubkey: String,
 ) {
+    let mut backoff = new_stream_backoff();
+    loop {
+        match run_position_stream(&store, &pool, &market, &pubkey, &mut backoff).await {
+            Ok(()) => warn!("positions stream ended"),
+            Err(e) => error!("positions stream failed: {}", e),
+        }
+        reconnect_delay(&store, VegaStream::Positions, &mut backoff).await;
+    }
+}
+
+async fn run_position_stream(
+    store: &Arc<Mutex<VegaStore>>,
+    pool: &Arc<Mutex<NodePool>>,
+    market: &String,
+    pubkey: &String,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
     use vega_protobufs::datanode::api::v2::observe_positions_response::Response;
-    info!("starting positions stream for party: {}...", &*pubkey);
-    let mut stream = match clt
+    let (mut clt, generation) = connect_current(pool).await?;
+    info!("starting positions stream for party: {}...", pubkey);
+    // the stream starts with a snapshot of our positions, that's what brings
+    // the store up to date after a reconnect
+    let mut stream = clt
         .observe_positions(ObservePositionsRequest {
-            party_id: Some(pubkey),
-            market_id: Some(market),
+            party_id: Some(pubkey.clone()),
+            market_id: Some(market.clone()),
             include_derived_parties: Some(false),
         })
-        .await
-    {
-        Ok(s) => s.into_inner(),
-        Err(e) => panic!("{:?}", e),
-    };
+        .await?
+        .into_inner();
+    store.lock().unwrap().set_stream_connected(VegaStream::Positions, true);
 
-    while let Some(item) = stream.next().await {
-        match item {
-            Ok(resp) => match resp.response {
-                Some(r) => match r {
-                    Response::Snapshot(o) => {
-                        store.lock().unwrap().save_positions(o.positions.clone())
-                    }
-                    Response::Updates(o) => {
-                        store.lock().unwrap().save_positions(o.positions.clone())
-                    }
-                },
-                _ => {}
-            },
-            Err(e) => {
-                error!("could not load position: {} - {}", e, e.message());
-            }
+    while let Some(item) = next_item(&mut stream, pool, generation).await {
+        let resp = item?;
+        let mut s = store.lock().unwrap();
+        match resp.response {
+            Some(Response::Snapshot(o)) => s.replace_positions(o.positions.clone()),
+            Some(Response::Updates(o)) => s.save_positions(o.positions.clone()),
+            None => {}
         }
+        s.touch_stream(VegaStream::Positions);
+        backoff.reset();
     }
+    return Ok(());
 }
 
 async fn update_trades_forever(
     store: Arc<Mutex<VegaStore>>,
-    mut clt: TradingDataServiceClient<tonic::transport::Channel>,
+    pool: Arc<Mutex<NodePool>>,
     market: String,
-    pubkey: String,
 ) {
+    let mut backoff = new_stream_backoff();
+    loop {
+        match run_trades_stream(&store, &pool, &market, &mut backoff).await {
+            Ok(()) => warn!("trades stream ended"),
+            Err(e) => error!("trades stream failed: {}", e),
+        }
+        reconnect_delay(&store, VegaStream::Trades, &mut backoff).await;
+    }
+}
+
+async fn run_trades_stream(
+    store: &Arc<Mutex<VegaStore>>,
+    pool: &Arc<Mutex<NodePool>>,
+    market: &String,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    let (mut clt, generation) = connect_current(pool).await?;
     info!("Starting trades stream.");
-    let mut stream = match clt
+    // trades missed while disconnected are not recovered, they only feed the
+    // lambda / kappa estimation which copes with a gap in the sample
+    let mut stream = clt
         .observe_trades(ObserveTradesRequest {
             party_ids: vec![],
-            market_ids: vec![market],
+            market_ids: vec![market.clone()],
         })
-        .await
+        .await?
+        .into_inner();
+    store.lock().unwrap().set_stream_connected(VegaStream::Trades, true);
+
+    while let Some(item) = next_item(&mut stream, pool, generation).await {
+        let resp = item?;
+        let mut s = store.lock().unwrap();
+        for t in resp.trades.iter() {
+            s.save_trade(t);
+        }
+        s.touch_stream(VegaStream::Trades);
+        backoff.reset();
+    }
+    return Ok(());
+}
+
+async fn update_fills_forever(
+    store: Arc<Mutex<VegaStore>>,
+    pool: Arc<Mutex<NodePool>>,
+    market: String,
+    pubkey: String,
+) {
+    // with no fill stored yet the backfill covers the whole session, so a fill
+    // between starting up and the first subscription isn't lost either
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+    let session_start = now.as_nanos() as u64;
+    let mut backoff = new_stream_backoff();
+    loop {
+        match run_fills_stream(&store, &pool, &market, &pubkey, session_start, &mut backoff).await {
+            Ok(()) => warn!("fills stream ended"),
+            Err(e) => error!("fills stream failed: {}", e),
+        }
+        reconnect_delay(&store, VegaStream::Fills, &mut backoff).await;
+    }
+}
+
+// Our trades only. Unlike the market trades these back the P&L, so on every
+// (re)connect the fills we missed are loaded from the datanode, starting at
+// our last fill or at `session_start` (nanos) if we have none; subscribing
+// first means the backfill and the stream overlap rather than leave a gap,
+// save_fills drops the duplicates.
+async fn run_fills_stream(
+    store: &Arc<Mutex<VegaStore>>,
+    pool: &Arc<Mutex<NodePool>>,
+    market: &String,
+    pubkey: &String,
+    session_start: u64,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    let (mut clt, generation) = connect_current(pool).await?;
+    info!("starting fills stream for party: {}...", pubkey);
+    let mut stream = clt
+        .observe_trades(ObserveTradesRequest {
+            party_ids: vec![pubkey.clone()],
+            market_ids: vec![market.clone()],
+        })
+        .await?
+        .into_inner();
+
+    let since = store.lock().unwrap().last_fill_timestamp().unwrap_or(session_start);
+    let missed = list_trades_since(&mut clt, market, vec![pubkey.clone()], since as i64).await?;
     {
-        Ok(s) => s.into_inner(),
-        Err(e) => panic!("{:?}", e),
-    };
+        let mut s = store.lock().unwrap();
+        for t in missed.iter() {
+            s.save_fills(t);
+        }
+    }
+    store.lock().unwrap().set_stream_connected(VegaStream::Fills, true);
 
-    while let Some(item) = stream.next().await {
-        match item {
-            Ok(resp) => {
-                for t in resp.trades.iter() {
-                    store.lock().unwrap().save_trade(t);
+    while let Some(item) = next_item(&mut stream, pool, generation).await {
+        let resp = item?;
+        let mut s = store.lock().unwrap();
+        for t in resp.trades.iter() {
+            s.save_fills(t);
+        }
+        s.touch_stream(VegaStream::Fills);
+        backoff.reset();
+    }
+    return Ok(());
+}
+
+async fn update_orders_forever(
+    store: Arc<Mutex<VegaStore>>,
+    pool: Arc<Mutex<NodePool>>,
+    market: String,
+    pubkey: String,
+) {
+    let mut backoff = new_stream_backoff();
+    loop {
+        match run_orders_stream(&store, &pool, &market, &pubkey, &mut backoff).await {
+            Ok(()) => warn!("orders stream ended"),
+            Err(e) => error!("orders stream failed: {}", e),
+        }
+        reconnect_delay(&store, VegaStream::Orders, &mut backoff).await;
+    }
+}
+
+async fn run_orders_stream(
+    store: &Arc<Mutex<VegaStore>>,
+    pool: &Arc<Mutex<NodePool>>,
+    market: &String,
+    pubkey: &String,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    use vega_protobufs::datanode::api::v2::observe_orders_response::Response;
+    let (mut clt, generation) = connect_current(pool).await?;
+    info!("starting orders stream for party: {}...", pubkey);
+    let mut stream = clt
+        .observe_orders(ObserveOrdersRequest {
+            market_ids: vec![market.clone()],
+            party_ids: vec![pubkey.clone()],
+            exclude_liquidity: None,
+        })
+        .await?
+        .into_inner();
+    store.lock().unwrap().set_stream_connected(VegaStream::Orders, true);
+
+    // the snapshot can come in several pages, only replace our view of the
+    // book once we have all of it
+    let mut snapshot: Vec<Order> = vec![];
+    while let Some(item) = next_item(&mut stream, pool, generation).await {
+        let resp = item?;
+        let mut s = store.lock().unwrap();
+        match resp.response {
+            Some(Response::Snapshot(page)) => {
+                snapshot.extend(page.orders.into_iter());
+                if page.last_page {
+                    info!("orders snapshot with {} orders", snapshot.len());
+                    s.replace_orders(std::mem::take(&mut snapshot));
                 }
             }
-            Err(e) => {
-                error!("could not load trade: {} - {}", e, e.message());
+            Some(Response::Updates(o)) => s.save_orders(o.orders),
+            None => {}
+        }
+        s.touch_stream(VegaStream::Orders);
+        backoff.reset();
+    }
+    return Ok(());
+}
+
+async fn update_depth_forever(
+    store: Arc<Mutex<VegaStore>>,
+    pool: Arc<Mutex<NodePool>>,
+    market: String,
+) {
+    let mut backoff = new_stream_backoff();
+    loop {
+        match run_depth_stream(&store, &pool, &market, &mut backoff).await {
+            Ok(()) => warn!("market depth stream ended"),
+            Err(e) => error!("market depth stream failed: {}", e),
+        }
+        reconnect_delay(&store, VegaStream::Depth, &mut backoff).await;
+    }
+}
+
+async fn load_depth_snapshot(
+    store: &Arc<Mutex<VegaStore>>,
+    clt: &mut TradingDataServiceClient<tonic::transport::Channel>,
+    market: &String,
+) -> Result<(), Error> {
+    let resp = clt
+        .get_latest_market_depth(GetLatestMarketDepthRequest {
+            market_id: market.clone(),
+            ..Default::default()
+        })
+        .await?;
+    let depth = resp.get_ref();
+    info!("market depth snapshot at sequence number {}", depth.sequence_number);
+    store
+        .lock()
+        .unwrap()
+        .set_book(VegaBook::from_levels(&depth.buy, &depth.sell, depth.sequence_number));
+    return Ok(());
+}
+
+async fn run_depth_stream(
+    store: &Arc<Mutex<VegaStore>>,
+    pool: &Arc<Mutex<NodePool>>,
+    market: &String,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    let (mut clt, generation) = connect_current(pool).await?;
+    info!("starting market depth stream for market: {}...", market);
+    // subscribe before taking the snapshot so no update falls in between,
+    // updates the snapshot already covers are skipped by sequence number
+    let mut stream = clt
+        .observe_markets_depth_updates(ObserveMarketsDepthUpdatesRequest {
+            market_ids: vec![market.clone()],
+        })
+        .await?
+        .into_inner();
+    load_depth_snapshot(store, &mut clt, market).await?;
+    store.lock().unwrap().set_stream_connected(VegaStream::Depth, true);
+
+    while let Some(item) = next_item(&mut stream, pool, generation).await {
+        let resp = item?;
+        let mut resync = false;
+        {
+            let mut s = store.lock().unwrap();
+            for u in resp.update.iter().filter(|u| &u.market_id == market) {
+                if let Err(gap) = s.apply_depth_update(u) {
+                    warn!(
+                        "market depth sequence gap, expected update after {} but got one after {}, resyncing",
+                        gap.expected, gap.got
+                    );
+                    resync = true;
+                    break;
+                }
             }
+            s.touch_stream(VegaStream::Depth);
+        }
+        if resync {
+            load_depth_snapshot(store, &mut clt, market).await?;
         }
+        backoff.reset();
     }
+    return Ok(());
+}
+
+async fn update_accounts_forever(
+    store: Arc<Mutex<VegaStore>>,
+    pool: Arc<Mutex<NodePool>>,
+    pubkey: String,
+) {
+    let mut backoff = new_stream_backoff();
+    loop {
+        match run_accounts_stream(&store, &pool, &pubkey, &mut backoff).await {
+            Ok(()) => warn!("accounts stream ended"),
+            Err(e) => error!("accounts stream failed: {}", e),
+        }
+        reconnect_delay(&store, VegaStream::Accounts, &mut backoff).await;
+    }
+}
+
+async fn run_accounts_stream(
+    store: &Arc<Mutex<VegaStore>>,
+    pool: &Arc<Mutex<NodePool>>,
+    pubkey: &String,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    use vega_protobufs::datanode::api::v2::observe_accounts_response::Response;
+    let (mut clt, generation) = connect_current(pool).await?;
+    info!("starting accounts stream for party: {}...", pubkey);
+    // no market filter, it would leave out the general accounts;
+    // save_accounts drops other markets
+    let mut stream = clt
+        .observe_accounts(ObserveAccountsRequest {
+            party_id: pubkey.clone(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+    store.lock().unwrap().set_stream_connected(VegaStream::Accounts, true);
+
+    let mut snapshot: Vec<AccountBalance> = vec![];
+    while let Some(item) = next_item(&mut stream, pool, generation).await {
+        let resp = item?;
+        let mut s = store.lock().unwrap();
+        match resp.response {
+            Some(Response::Snapshot(page)) => {
+                snapshot.extend(page.accounts.into_iter());
+                if page.last_page {
+                    s.replace_accounts(std::mem::take(&mut snapshot));
+                }
+            }
+            Some(Response::Updates(u)) => s.save_accounts(u.accounts),
+            None => {}
+        }
+        s.touch_stream(VegaStream::Accounts);
+        backoff.reset();
+    }
+    return Ok(());
 }
 
 pub fn convert_nanos_since_unix_epoch_datetime(t: u64) -> DateTime<Local> {
@@ -386,3 +1314,21 @@ impl Error {
         }
     }
 }
+
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_live() {
+        let mut o = Order::default();
+        o.status = order::Status::Active as i32;
+        assert!(is_live(&o));
+        o.status = order::Status::Parked as i32;
+        assert!(is_live(&o));
+        for status in [order::Status::Filled, order::Status::Cancelled, order::Status::Expired, order::Status::Stopped] {
+            o.status = status as i32;
+            assert!(!is_live(&o));
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use log::{error, info, warn};
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::WebSocket;
+
+use crate::backoff::Backoff;
+
+pub type WsSocket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+// The feeds use blocking tungstenite sockets. A read timeout lets the read
+// loop wake up periodically so it can send application level pings even when
+// the venue is quiet.
+pub fn set_read_timeout(socket: &mut WsSocket, timeout: Option<Duration>) -> io::Result<()> {
+    match socket.get_mut() {
+        MaybeTlsStream::Plain(s) => s.set_read_timeout(timeout),
+        MaybeTlsStream::NativeTls(s) => s.get_ref().set_read_timeout(timeout),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_timeout(e: &tungstenite::Error) -> bool {
+    match e {
+        tungstenite::Error::Io(io_err) => {
+            io_err.kind() == io::ErrorKind::WouldBlock || io_err.kind() == io::ErrorKind::TimedOut
+        }
+        _ => false,
+    }
+}
+
+pub fn timeout_error(msg: &str) -> tungstenite::Error {
+    return tungstenite::Error::Io(io::Error::new(io::ErrorKind::TimedOut, msg.to_string()));
+}
+
+// Keeps a feed alive forever: `run` connects, subscribes and reads until the
+// connection drops, resetting the backoff once it gets data. We then log the
+// cause, wait with exponential backoff + jitter and call it again. Blocks the
+// calling thread.
+pub fn reconnect_forever<E: fmt::Display>(venue: &str, mut run: impl FnMut(&mut Backoff) -> Result<(), E>) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        match run(&mut backoff) {
+            Ok(()) => warn!("{} websocket closed by server", venue),
+            Err(e) => error!("{} websocket disconnected: {}", venue, e),
+        }
+
+        let delay = backoff.next_delay();
+        info!(
+            "reconnecting to {} in {:.1}s (attempt {})",
+            venue,
+            delay.as_secs_f64(),
+            backoff.attempt()
+        );
+        thread::sleep(delay);
+    }
+}